@@ -0,0 +1,153 @@
+//! Throughput of the response body path, from the tokio task to a Varnish-like consumer pulling
+//! 16KB at a time, comparing one message per chunk over a `channel(1)` (how bodies used to be
+//! moved) with `relay::pump()`/`Drain`. The origin always has its next chunk ready, which is the
+//! best case for coalescing, a real one will see smaller gains on small chunks.
+//!
+//! Run with `cargo bench --bench relay`.
+
+#[path = "../src/relay.rs"]
+#[allow(dead_code)]
+mod relay;
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use relay::{BodyMsg, ChunkSource, Drain};
+use tokio::sync::mpsc;
+
+const OBJECT_SIZE: usize = 256 * 1024 * 1024;
+// what Varnish typically asks for, see the `fetch_chunksize` parameter
+const READ_SIZE: usize = 16 * 1024;
+
+struct Msg(Bytes);
+
+impl From<Bytes> for Msg {
+    fn from(bytes: Bytes) -> Self {
+        Msg(bytes)
+    }
+}
+
+impl BodyMsg for Msg {
+    type Error = Infallible;
+
+    fn into_bytes(self) -> Result<Bytes, Infallible> {
+        Ok(self.0)
+    }
+}
+
+// an origin sending `data` in `chunk_size` pieces
+struct Origin {
+    data: Bytes,
+    chunk_size: usize,
+}
+
+impl ChunkSource for Origin {
+    type Error = Infallible;
+
+    fn chunk(&mut self) -> impl Future<Output = Result<Option<Bytes>, Infallible>> {
+        let len = self.chunk_size.min(self.data.len());
+        let chunk = (len > 0).then(|| self.data.split_to(len));
+        async move { Ok(chunk) }
+    }
+}
+
+fn before(rt: &tokio::runtime::Runtime, data: &Bytes, chunk_size: usize) -> Duration {
+    let (tx, mut rx) = mpsc::channel::<Bytes>(1);
+    let mut origin = Origin {
+        data: data.clone(),
+        chunk_size,
+    };
+    let start = Instant::now();
+    rt.spawn(async move {
+        while let Ok(Some(chunk)) = origin.chunk().await {
+            if tx.send(chunk).await.is_err() {
+                return;
+            }
+        }
+    });
+    let mut buf = vec![0; READ_SIZE];
+    let mut total = 0;
+    let mut pending: Option<(Bytes, usize)> = None;
+    loop {
+        // the old `BackendResp::read()`: block for every chunk until `buf` is full
+        let mut n = 0;
+        while n < buf.len() {
+            let (bytes, cursor) = match pending.take() {
+                Some(p) => p,
+                None => match rx.blocking_recv() {
+                    Some(bytes) => (bytes, 0),
+                    None => break,
+                },
+            };
+            let used = (bytes.len() - cursor).min(buf.len() - n);
+            buf[n..n + used].copy_from_slice(&bytes[cursor..cursor + used]);
+            n += used;
+            if cursor + used < bytes.len() {
+                pending = Some((bytes, cursor + used));
+            }
+        }
+        if n == 0 {
+            break;
+        }
+        std::hint::black_box(&buf);
+        total += n;
+    }
+    assert_eq!(total, data.len());
+    start.elapsed()
+}
+
+fn after(rt: &tokio::runtime::Runtime, data: &Bytes, chunk_size: usize) -> Duration {
+    let (tx, mut rx) = mpsc::channel::<Msg>(relay::PIPELINE_DEPTH);
+    let mut origin = Origin {
+        data: data.clone(),
+        chunk_size,
+    };
+    let start = Instant::now();
+    rt.spawn(async move {
+        let _ = relay::pump(&mut origin, &tx).await;
+    });
+    let mut buf = vec![0; READ_SIZE];
+    let mut drain = Drain::default();
+    let mut total = 0;
+    loop {
+        let Ok(n) = drain.read(&mut rx, &mut buf);
+        if n == 0 {
+            break;
+        }
+        std::hint::black_box(&buf);
+        total += n;
+    }
+    assert_eq!(total, data.len());
+    start.elapsed()
+}
+
+#[expect(clippy::cast_precision_loss)]
+fn throughput(d: Duration) -> f64 {
+    OBJECT_SIZE as f64 / (1024.0 * 1024.0) / d.as_secs_f64()
+}
+
+fn main() {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let data = Bytes::from(vec![b'x'; OBJECT_SIZE]);
+
+    println!("{OBJECT_SIZE} bytes, read {READ_SIZE} bytes at a time");
+    for chunk_size in [1024, 4 * 1024, 16 * 1024, 256 * 1024] {
+        // best of three
+        let before = (0..3)
+            .map(|_| before(&rt, &data, chunk_size))
+            .min()
+            .unwrap();
+        let after = (0..3).map(|_| after(&rt, &data, chunk_size)).min().unwrap();
+        println!(
+            "chunks of {chunk_size:>6} bytes: before {:>8.1} MB/s, after {:>8.1} MB/s ({:.1}x)",
+            throughput(before),
+            throughput(after),
+            before.as_secs_f64() / after.as_secs_f64()
+        );
+    }
+}
@@ -1,21 +1,38 @@
 pub mod reqwest_private {
     use std::boxed::Box;
-    use std::io::Write;
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use std::ffi::CStr;
+    use std::fmt::Write as _;
+    use std::marker::PhantomData;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
     use std::os::raw::{c_uint, c_void};
-    use std::sync::Mutex;
-    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+    use std::sync::{Arc, Condvar, LazyLock, Mutex, OnceLock, PoisonError, RwLock, Weak};
+    use std::task::Poll;
     use std::time::{Duration, Instant, SystemTime};
 
-    use anyhow::Error;
-    use bytes::Bytes;
+    use anyhow::{Context as _, Error};
+    use bytes::{Bytes, BytesMut};
+    use hyper_util::client::legacy::connect::HttpInfo;
     use reqwest::{Client, Url};
-    use tokio::sync::mpsc::{Receiver, Sender, UnboundedSender};
-    use varnish::ffi::{BS_CACHED, BS_ERROR, BS_NONE};
+    use tokio::sync::mpsc::error::TryRecvError;
+    use tokio::sync::mpsc::{Receiver, Sender};
+    use tokio::sync::{Notify, Semaphore, oneshot, watch};
+    use varnish::ffi::{
+        BS_CACHED, BS_ERROR, BS_NONE, VMOD_PRIV_METHODS_MAGIC, VRT_priv_task, VSLb_ts, VSLbt,
+        busyobj, http_SetHeader, txt, vmod_priv, vmod_priv_methods, vtim_dur, vtim_real,
+    };
     use varnish::vcl::{Backend, StrOrBytes, VclBackend, VclResponse};
     use varnish::vcl::{
-        Buffer, Ctx, Event, LogTag, Probe, Request as ProbeRequest, VclError, VclResult, log,
+        Buffer, Ctx, Event, HttpHeaders, LogTag, Probe, Request as ProbeRequest, VclError,
+        VclResult, Workspace, log,
     };
 
+    use crate::relay::{self, BodyMsg, ChunkSource, Drain};
+    use crate::x509;
+
     pub struct ProbeState {
         spec: Probe,
         history: AtomicU64,
@@ -28,68 +45,703 @@ pub mod reqwest_private {
     pub struct client {
         pub name: String,
         pub be: Backend<VCLBackend, BackendResp>,
+        pub cancel_at_task_end: bool,
+        // only written to during vcl_init
+        pub templates: RwLock<HashMap<String, Template>>,
+        // shared with the backend, for the requests handed to it by `backend_for()`
+        pub variants: Arc<Variants>,
     }
 
     pub struct VCLBackend {
         pub name: String,
         pub bgt: *const BgThread,
-        pub client: Client,
+        pub runtime: Arc<BgRuntime>,
+        pub client: Arc<Client>,
         pub probe_state: Option<ProbeState>,
         pub https: bool,
         pub base_url: Option<String>,
+        // how many bytes of bereq body can wait for the origin to take them
+        pub req_body_buffer: usize,
+        // lowercase, stripped in both directions, on top of the hop-by-hop headers
+        pub strip_headers: Vec<String>,
+        pub variants: Arc<Variants>,
     }
 
-    // silly helper until varnish-rs provides something more ergonomic
-    fn sob_helper<'a>(sob: &'a StrOrBytes) -> &'a str {
-        match sob {
-            StrOrBytes::Bytes(_) => panic!("{sob:?} isn't a string"),
-            StrOrBytes::Utf8(s) => s,
+    // how many variant clients a `client` keeps around, the least recently used ones are dropped
+    // first, along with their pools
+    const MAX_VARIANTS: usize = 16;
+
+    // the clients for requests overriding `follow` or the proxy, built on first use
+    pub struct Variants {
+        settings: ClientSettings,
+        cache: Mutex<VariantCache>,
+    }
+
+    #[derive(Default)]
+    struct VariantCache {
+        // each client with the last time it was used, in `uses` ticks
+        clients: HashMap<ClientSettings, (Client, u64)>,
+        uses: u64,
+    }
+
+    impl VariantCache {
+        fn get(&mut self, settings: &ClientSettings) -> Option<Client> {
+            self.uses += 1;
+            let (client, last_used) = self.clients.get_mut(settings)?;
+            *last_used = self.uses;
+            Some(client.clone())
+        }
+
+        fn insert(&mut self, settings: ClientSettings, client: Client) {
+            if self.clients.len() >= MAX_VARIANTS
+                && let Some(oldest) = self
+                    .clients
+                    .iter()
+                    .min_by_key(|(_, (_, last_used))| *last_used)
+                    .map(|(k, _)| k.clone())
+            {
+                self.clients.remove(&oldest);
+            }
+            self.clients.insert(settings, (client, self.uses));
         }
     }
 
-    #[allow(clippy::extra_unused_lifetimes)]
-    impl<'a> VclBackend<BackendResp> for VCLBackend {
-        fn get_response(&self, ctx: &mut Ctx<'_>) -> VclResult<Option<BackendResp>> {
-            unsafe extern "C" fn body_send_iterate(
-                priv_: *mut c_void,
-                _flush: c_uint,
-                ptr: *const c_void,
-                l: isize,
-            ) -> i32 {
-                // nothing to do
-                if ptr.is_null() || l == 0 {
-                    return 0;
+    impl Variants {
+        pub fn new(settings: ClientSettings) -> Self {
+            Variants {
+                settings,
+                cache: Mutex::default(),
+            }
+        }
+
+        // the client to use for a request overriding `follow` and/or the proxy, `name` being the
+        // one of the `client`
+        fn get(&self, name: &str, follow: Option<i64>, proxy: Option<&str>) -> VclResult<Client> {
+            let mut settings = self.settings.clone();
+            if let Some(follow) = follow {
+                settings.follow = follow;
+            }
+            if let Some(proxy) = proxy {
+                settings.http_proxy = Some(proxy.to_owned());
+                settings.https_proxy = Some(proxy.to_owned());
+            }
+            if let Some(client) = self
+                .cache
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get(&settings)
+            {
+                return Ok(client);
+            }
+            // building can be slow (TLS setup), don't hold up the other workers meanwhile
+            let client = settings.build(name)?;
+            let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+            // somebody may have beaten us to it
+            if let Some(client) = cache.get(&settings) {
+                return Ok(client);
+            }
+            cache.insert(settings, client.clone());
+            Ok(client)
+        }
+    }
+
+    // see RFC 9110, section 7.6.1
+    const HOP_BY_HOP: [&str; 6] = [
+        "connection",
+        "proxy-connection",
+        "keep-alive",
+        "te",
+        "transfer-encoding",
+        "upgrade",
+    ];
+
+    // the lowercase names of the headers that must not be forwarded: the hop-by-hop ones, the ones
+    // listed by the `connection` header(s) and the `client`'s own
+    fn hop_by_hop<'a>(connection: impl Iterator<Item = &'a [u8]>, extra: &[String]) -> Vec<String> {
+        let mut names: Vec<String> = HOP_BY_HOP.iter().map(ToString::to_string).collect();
+        for value in connection {
+            names.extend(
+                String::from_utf8_lossy(value)
+                    .split(',')
+                    .map(|name| name.trim().to_ascii_lowercase())
+                    .filter(|name| !name.is_empty()),
+            );
+        }
+        names.extend(extra.iter().cloned());
+        names
+    }
+
+    // parse the comma-separated `strip_headers` argument of the constructor
+    pub fn parse_header_list(list: &str) -> Result<Vec<String>, String> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                    .map(|name| name.as_str().to_string())
+                    .map_err(|_| format!("invalid header name ({name})"))
+            })
+            .collect()
+    }
+
+    // bereq bodies are sent in pieces of at most this size, to make `req_body_buffer` meaningful
+    const REQ_BODY_PIECE: usize = 64 * 1024;
+
+    type BodySender = Sender<Result<Bytes, String>>;
+
+    // the bereq body as the origin gets it, telling `process_req()` when it's over
+    struct BereqBody {
+        rx: tokio_stream::wrappers::ReceiverStream<Result<Bytes, String>>,
+        sent: Option<oneshot::Sender<()>>,
+    }
+
+    impl tokio_stream::Stream for BereqBody {
+        type Item = Result<Bytes, String>;
+
+        fn poll_next(
+            mut self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> Poll<Option<Self::Item>> {
+            let item = Pin::new(&mut self.rx).poll_next(cx);
+            if let Poll::Ready(None) = item
+                && let Some(sent) = self.sent.take()
+            {
+                let _ = sent.send(());
+            }
+            item
+        }
+    }
+
+    // everything needed to build a reqwest::Client, compared to decide if two VCLs can share a pool
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    #[allow(clippy::struct_excessive_bools)]
+    pub struct ClientSettings {
+        pub follow: i64,
+        pub timeout: Option<Duration>,
+        pub connect_timeout: Option<Duration>,
+        pub auto_gzip: bool,
+        pub auto_deflate: bool,
+        pub auto_brotli: bool,
+        pub accept_invalid_certs: bool,
+        pub accept_invalid_hostnames: bool,
+        pub http_proxy: Option<String>,
+        pub https_proxy: Option<String>,
+        pub redirect_rules: RedirectRules,
+    }
+
+    // restrictions on the redirects we follow, on top of `follow`
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+    #[allow(clippy::struct_excessive_bools)]
+    pub struct RedirectRules {
+        pub same_host: bool,
+        pub no_downgrade: bool,
+        pub no_private: bool,
+    }
+
+    impl RedirectRules {
+        // `policy` is a comma-separated list of rules
+        pub fn parse(policy: &str) -> VclResult<Self> {
+            let mut rules = RedirectRules::default();
+            for rule in policy.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+                match rule {
+                    "same_host" => rules.same_host = true,
+                    "no_downgrade" => rules.no_downgrade = true,
+                    "no_private" => rules.no_private = true,
+                    _ => return Err(format!("unknown redirect rule ({rule})").into()),
                 }
-                #[expect(clippy::cast_sign_loss)]
-                let (body_chan, buf) = unsafe {
-                    let body_chan = priv_
-                        .cast::<UnboundedSender<Result<Bytes, String>>>()
-                        .as_mut()
-                        .unwrap();
-                    let buf = std::slice::from_raw_parts(ptr.cast::<u8>(), l as usize);
-                    (body_chan, buf)
+            }
+            Ok(rules)
+        }
+
+        fn allow(self, previous: &[Url], next: &Url) -> bool {
+            if self.same_host && previous.first().map(Url::host) != Some(next.host()) {
+                return false;
+            }
+            if self.no_downgrade
+                && previous.last().map(Url::scheme) == Some("https")
+                && next.scheme() != "https"
+            {
+                return false;
+            }
+            !(self.no_private && is_private(next))
+        }
+    }
+
+    // only IP literals can be judged here, hostnames are checked once resolved, by `TimedResolver`
+    fn is_private(url: &Url) -> bool {
+        url.host_str().is_some_and(|host| {
+            host.trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse()
+                .is_ok_and(is_private_ip)
+        })
+    }
+
+    fn is_private_ip(ip: IpAddr) -> bool {
+        let v4 = |ip: Ipv4Addr| {
+            ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+        };
+        match ip {
+            IpAddr::V4(ip) => v4(ip),
+            IpAddr::V6(ip) => {
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+                    || ip.to_ipv4_mapped().is_some_and(v4)
+            }
+        }
+    }
+
+    // what happened while the task was sending its request, as seen by the only places that know:
+    // the redirect policy, the resolver and the connector
+    #[derive(Clone, Default)]
+    struct Trace {
+        redirects: Cell<usize>,
+        // a new connection was opened, rather than one reused from the pool
+        connected: Cell<bool>,
+        dns: Cell<Duration>,
+        // includes `dns`, as the connector calls the resolver
+        connect: Cell<Duration>,
+        connect_timeout: Option<Duration>,
+    }
+
+    tokio::task_local! {
+        static TRACE: Trace;
+    }
+
+    // the system resolver, timed, and refusing private addresses after a redirect if the
+    // `no_private` redirect rule asks for it
+    struct TimedResolver {
+        no_private: bool,
+    }
+
+    // a failed lookup, typed so that `error_kind()` can tell it from other connection failures
+    #[derive(Debug)]
+    struct DnsError(std::io::Error);
+
+    impl std::fmt::Display for DnsError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "dns error: {}", self.0)
+        }
+    }
+
+    impl std::error::Error for DnsError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    // a redirect to a hostname resolving to an address `no_private` forbids
+    #[derive(Debug)]
+    struct PrivateRedirect(String);
+
+    impl std::fmt::Display for PrivateRedirect {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "redirect to {} refused, it resolves to a private address",
+                self.0
+            )
+        }
+    }
+
+    impl std::error::Error for PrivateRedirect {}
+
+    // a request reqwest can't even build
+    #[derive(Debug)]
+    struct InvalidRequest(String);
+
+    impl std::fmt::Display for InvalidRequest {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(&self.0)
+        }
+    }
+
+    impl std::error::Error for InvalidRequest {}
+
+    impl reqwest::dns::Resolve for TimedResolver {
+        fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+            let host = name.as_str().to_owned();
+            let no_private = self.no_private;
+            Box::pin(async move {
+                let start = Instant::now();
+                let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                    .await
+                    .map_err(DnsError)?
+                    .collect();
+                let _ = TRACE.try_with(|t| t.dns.set(t.dns.get() + start.elapsed()));
+                // any private address is enough to refuse, the connector could pick it
+                if no_private
+                    && TRACE.try_with(|t| t.redirects.get() > 0).unwrap_or(false)
+                    && addrs.iter().any(|a| is_private_ip(a.ip()))
+                {
+                    return Err(Box::new(PrivateRedirect(host)) as _);
+                }
+                Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+            })
+        }
+    }
+
+    // the connector is only called when the pool has no idle connection to offer, if the pool
+    // comes up with one while we connect, the connection finishes in the background, outside of
+    // the request's task
+    #[derive(Clone)]
+    struct TrackConnects;
+
+    impl<S> tower::Layer<S> for TrackConnects {
+        type Service = ConnectTracker<S>;
+
+        fn layer(&self, inner: S) -> Self::Service {
+            ConnectTracker(inner)
+        }
+    }
+
+    #[derive(Clone)]
+    struct ConnectTracker<S>(S);
+
+    impl<S, R> tower::Service<R> for ConnectTracker<S>
+    where
+        S: tower::Service<R>,
+        S::Future: Send + 'static,
+        S::Error: From<std::io::Error>,
+    {
+        type Response = S::Response;
+        type Error = S::Error;
+        type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+        fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), S::Error>> {
+            self.0.poll_ready(cx)
+        }
+
+        fn call(&mut self, req: R) -> Self::Future {
+            let connecting = self.0.call(req);
+            let timeout = TRACE.try_with(|t| t.connect_timeout).ok().flatten();
+            Box::pin(async move {
+                let start = Instant::now();
+                let conn = match timeout {
+                    None => connecting.await,
+                    Some(timeout) => tokio::time::timeout(timeout, connecting)
+                        .await
+                        .unwrap_or_else(|_| {
+                            Err(std::io::Error::new(
+                                std::io::ErrorKind::TimedOut,
+                                "connect timeout",
+                            )
+                            .into())
+                        }),
                 };
-                let bytes = Bytes::copy_from_slice(buf);
+                let _ = TRACE.try_with(|t| {
+                    t.connected.set(true);
+                    t.connect.set(t.connect.get() + start.elapsed());
+                });
+                conn
+            })
+        }
+    }
 
-                body_chan.send(Ok(bytes)).is_err().into()
+    impl ClientSettings {
+        pub fn build(&self, vcl_name: &str) -> VclResult<Client> {
+            let mut rcb = reqwest::ClientBuilder::new()
+                .brotli(self.auto_brotli)
+                .deflate(self.auto_deflate)
+                .gzip(self.auto_gzip)
+                .danger_accept_invalid_certs(self.accept_invalid_certs)
+                .danger_accept_invalid_hostnames(self.accept_invalid_hostnames)
+                .tls_info(true)
+                .dns_resolver(Arc::new(TimedResolver {
+                    no_private: self.redirect_rules.no_private,
+                }))
+                .connector_layer(TrackConnects);
+            if let Some(t) = self.timeout {
+                rcb = rcb.timeout(t);
+            }
+            if let Some(t) = self.connect_timeout {
+                rcb = rcb.connect_timeout(t);
+            }
+            if let Some(ref proxy) = self.http_proxy {
+                rcb = rcb.proxy(reqwest::Proxy::http(proxy).map_err(|e| {
+                    VclError::new(format!(
+                        "reqwest: couldn't initialize {vcl_name}'s HTTP proxy ({e})"
+                    ))
+                })?);
+            }
+            if let Some(ref proxy) = self.https_proxy {
+                rcb = rcb.proxy(reqwest::Proxy::https(proxy).map_err(|e| {
+                    VclError::new(format!(
+                        "reqwest: couldn't initialize {vcl_name}'s HTTPS proxy ({e})"
+                    ))
+                })?);
             }
+            // mimicking Policy::none() and Policy::limited(), with our own rules on top
+            let follow = usize::try_from(self.follow).unwrap_or(0);
+            let rules = self.redirect_rules;
+            rcb = rcb.redirect(reqwest::redirect::Policy::custom(move |attempt| {
+                if follow == 0 || !rules.allow(attempt.previous(), attempt.url()) {
+                    attempt.stop()
+                } else if attempt.previous().len() > follow {
+                    attempt.error("too many redirects")
+                } else {
+                    let _ = TRACE.try_with(|t| t.redirects.set(attempt.previous().len()));
+                    attempt.follow()
+                }
+            }));
+            rcb.build().map_err(|e| {
+                VclError::new(format!("reqwest: couldn't initialize {vcl_name} ({e})"))
+            })
+        }
+    }
 
+    // pools shared across VCLs, only kept alive by the clients using them
+    type Pools = HashMap<String, (ClientSettings, Weak<Client>)>;
+    static POOLS: LazyLock<Mutex<Pools>> = LazyLock::new(Mutex::default);
+
+    // build a new client, or reuse the one registered under `pool_key` if its settings are identical
+    pub fn get_client(
+        vcl_name: &str,
+        settings: &ClientSettings,
+        pool_key: Option<&str>,
+    ) -> VclResult<Arc<Client>> {
+        let Some(key) = pool_key else {
+            return Ok(Arc::new(settings.build(vcl_name)?));
+        };
+        let mut pools = POOLS.lock().unwrap_or_else(PoisonError::into_inner);
+        pools.retain(|_, (_, pool)| pool.strong_count() > 0);
+        if let Some((pool_settings, pool)) = pools.get(key)
+            && let Some(client) = pool.upgrade()
+        {
+            if pool_settings == settings {
+                return Ok(client);
+            }
+            log(
+                LogTag::Debug,
+                format!(
+                    "reqwest: {vcl_name} has different settings than the other users of pool_key {key}, creating a new pool"
+                ),
+            );
+        }
+        let client = Arc::new(settings.build(vcl_name)?);
+        pools.insert(key.to_owned(), (settings.clone(), Arc::downgrade(&client)));
+        Ok(client)
+    }
+
+    // the parts of bereq we need to build a URL must be strings, reject the rest
+    fn bereq_str<'a>(sob: &'a StrOrBytes, what: &str) -> Result<&'a str, ReqError> {
+        match sob {
+            StrOrBytes::Utf8(s) => Ok(s),
+            StrOrBytes::Bytes(b) => Err(ReqError::new(
+                "invalid_bereq",
+                format!("{what} isn't valid UTF-8 ({})", b.escape_ascii()),
+            )),
+        }
+    }
+
+    // add a header to `hdrs`, unlike `HttpHeaders::set_header()`, `value` can be any bytes HTTP
+    // allows, `ws` must be the workspace `hdrs` lives in
+    fn set_header_bytes(
+        ws: &mut Workspace,
+        hdrs: &mut HttpHeaders,
+        name: &str,
+        value: &[u8],
+    ) -> VclResult<()> {
+        if hdrs.raw.nhd >= hdrs.raw.shd {
+            return Err(VclError::new(format!("no more header slot for {name}")));
+        }
+        let line = [name.as_bytes(), b": ", value].concat();
+        let line = ws.copy_bytes_with_null(line)?;
+        unsafe { http_SetHeader(hdrs.raw, line.b) };
+        Ok(())
+    }
+
+    // log a Timestamp record the way Varnish does, `bo` must be valid
+    unsafe fn timestamp(bo: *mut busyobj, event: &CStr, when: SystemTime) {
+        let when = when
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64());
+        unsafe {
+            let bo = &mut *bo;
+            VSLb_ts(
+                bo.vsl.as_mut_ptr(),
+                event.as_ptr(),
+                bo.t_first,
+                &raw mut bo.t_prev,
+                vtim_real(when),
+            );
+        }
+    }
+
+    // "IP PORT", as found in BackendOpen records
+    fn ip_and_port(addr: Option<SocketAddr>) -> String {
+        addr.map_or_else(|| "- -".to_string(), |a| format!("{} {}", a.ip(), a.port()))
+    }
+
+    // what Varnish would put in beresp.proto
+    fn http_version(version: reqwest::Version) -> &'static str {
+        match version {
+            reqwest::Version::HTTP_09 => "HTTP/0.9",
+            reqwest::Version::HTTP_10 => "HTTP/1.0",
+            reqwest::Version::HTTP_2 => "HTTP/2.0",
+            reqwest::Version::HTTP_3 => "HTTP/3.0",
+            _ => "HTTP/1.1",
+        }
+    }
+
+    impl VCLBackend {
+        fn fetch(&self, ctx: &mut Ctx<'_>) -> Result<Option<BackendResp>, ReqError> {
             if !self.probe(ctx).0 {
-                return Err("unhealthy".into());
+                return Err(ReqError::new("unhealthy", "unhealthy"));
             }
 
-            let bereq = ctx.http_bereq.as_ref().unwrap();
+            // a request prepared with `client.backend_for()` replaces bereq entirely
+            let (mut req, body_tx) = match prepared_fetch(ctx, &self.name)? {
+                Some(mut req) => {
+                    req.body_mode = BodyMode::Stream;
+                    if req.follow.is_some() || req.proxy.is_some() {
+                        req.client = self
+                            .variants
+                            .get(&self.name, req.follow, req.proxy.as_deref())
+                            .map_err(|e| ReqError::new("builder", e.to_string()))?;
+                    }
+                    (req, None)
+                }
+                None => self.req_from_bereq(ctx)?,
+            };
+            let bo = unsafe { ctx.raw.bo.as_ref() }
+                .ok_or_else(|| ReqError::new("other", "no busyobj, not in a backend fetch"))?;
+            req.fetch_timeouts = FetchTimeouts::from_bo(bo);
+            let strip = hop_by_hop(
+                req.headers
+                    .iter()
+                    .filter(|(k, _)| k.eq_ignore_ascii_case("connection"))
+                    .map(|(_, v)| v.as_slice()),
+                &self.strip_headers,
+            );
+            req.headers
+                .retain(|(k, _)| !strip.iter().any(|s| s.eq_ignore_ascii_case(k)));
+            let mut resp_rx = unsafe { (*self.bgt).spawn_req(&self.runtime, req, true)?.rx };
+            // the request is already on its way, the origin gets the body as we read it
+            if let Some(body_tx) = body_tx {
+                Self::send_bereq_body(ctx, body_tx)?;
+            }
 
-            let sob = bereq.url().unwrap();
-            let bereq_url = sob_helper(&sob);
+            let Some(msg) = resp_rx.blocking_recv() else {
+                return Err(ReqError::new("abandoned", "request abandoned"));
+            };
+            let resp = match msg {
+                RespMsg::Hdrs(resp) => resp,
+                RespMsg::Err(e) => return Err(e.into()),
+                RespMsg::Chunk(_) => {
+                    return Err(ReqError::new(
+                        "other",
+                        "received body data before the response headers",
+                    ));
+                }
+            };
+            if resp.redirects > 0 {
+                ctx.log(
+                    LogTag::Notice,
+                    format!(
+                        "reqwest: final URL {} ({} redirects)",
+                        resp.url, resp.redirects
+                    ),
+                );
+            }
+            // same format as native backends, minus the file descriptor we don't have, plus the HTTP
+            // version and, for new TLS connections, the peer's subject and expiry
+            let mut open = format!(
+                "-1 {} {} {} {} {}",
+                self.name,
+                ip_and_port(resp.remote_addr),
+                ip_and_port(resp.local_addr),
+                if resp.connected { "connect" } else { "reuse" },
+                resp.version,
+            );
+            if resp.connected
+                && let Some(cert) = resp.peer_cert.as_deref().and_then(x509::parse)
+            {
+                let expiry = cert
+                    .not_after
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs());
+                write!(open, " \"{}\" {expiry}", cert.subject).unwrap();
+            }
+            ctx.log(LogTag::BackendOpen, open);
+            let t = &resp.timing;
+            unsafe {
+                if !t.dns.is_zero() {
+                    timestamp(ctx.raw.bo, c"Reqwest-DNS", t.start + t.dns);
+                }
+                if resp.connected {
+                    timestamp(
+                        ctx.raw.bo,
+                        c"Reqwest-Connected",
+                        t.start + t.dns + t.connect,
+                    );
+                }
+                timestamp(ctx.raw.bo, c"Reqwest-FirstByte", t.start + t.first_byte);
+            }
+            // from now on, dropping it logs the matching BackendClose
+            let backend_resp = BackendResp {
+                drain: Drain::default(),
+                chan: Some(resp_rx),
+                content_length: resp.content_length.and_then(|s| usize::try_from(s).ok()),
+                name: self.name.clone(),
+                bo: ctx.raw.bo,
+                timing: resp.timing.clone(),
+                remote_addr: resp.remote_addr,
+                keep_alive: keeps_alive(&resp),
+            };
+            let beresp = ctx
+                .http_beresp
+                .as_mut()
+                .ok_or_else(|| ReqError::new("other", "no beresp to fill"))?;
+            beresp.set_status(u16::try_from(resp.status).map_err(|_| {
+                ReqError::new("other", format!("invalid status ({})", resp.status))
+            })?);
+            beresp.set_proto(resp.version)?;
+            let strip = hop_by_hop(
+                resp.headers
+                    .get_all(reqwest::header::CONNECTION)
+                    .iter()
+                    .map(reqwest::header::HeaderValue::as_bytes),
+                &self.strip_headers,
+            );
+            for (k, v) in &resp.headers {
+                if strip.iter().any(|s| s == k.as_str()) {
+                    continue;
+                }
+                set_header_bytes(&mut ctx.ws, beresp, k.as_str(), v.as_bytes())
+                    .map_err(|e| ReqError::new("invalid_header", e.to_string()))?;
+            }
+            Ok(Some(backend_resp))
+        }
+
+        // build the request from bereq, if there's a body, the request will read it from the
+        // returned channel, see `send_bereq_body()`
+        fn req_from_bereq(
+            &self,
+            ctx: &mut Ctx<'_>,
+        ) -> Result<(Request, Option<BodySender>), ReqError> {
+            let bereq = ctx
+                .http_bereq
+                .as_ref()
+                .ok_or_else(|| ReqError::new("other", "no bereq to send"))?;
+
+            let sob = bereq
+                .url()
+                .ok_or_else(|| ReqError::new("invalid_bereq", "bereq.url is unset"))?;
+            let bereq_url = bereq_str(&sob, "bereq.url")?;
 
-            let url = if let Some(base_url) = &self.base_url {
-                // if the client has a base_url, prepend it to bereq.url
+            let base_url = BASE_URLS
+                .take(ctx, &self.name)
+                .or_else(|| self.base_url.clone());
+            let url = if let Some(base_url) = base_url {
+                // if the client (or this bereq) has a base_url, prepend it to bereq.url
                 format!("{base_url}{bereq_url}")
             } else if bereq_url.starts_with('/') {
                 // otherwise, if bereq.url looks like a path, try to find a host to build a full URL
                 if let Some(host) = bereq.header("host") {
-                    let host_str = sob_helper(&host);
+                    let host_str = bereq_str(&host, "bereq.http.host")?;
                     format!(
                         "{}://{}{}",
                         if self.https { "https" } else { "http" },
@@ -97,94 +749,151 @@ pub mod reqwest_private {
                         bereq_url
                     )
                 } else {
-                    return Err("no host found (reqwest.client doesn't have a base_url, bereq.url doesn't specify a host and bereq.http.host is unset)".into());
+                    return Err(ReqError::new(
+                        "no_host",
+                        "no host found (reqwest.client doesn't have a base_url, bereq.url doesn't specify a host and bereq.http.host is unset)",
+                    ));
                 }
             } else {
                 // else use bereq.url as-is
                 bereq_url.to_string()
             };
 
+            let method = bereq
+                .method()
+                .ok_or_else(|| ReqError::new("invalid_bereq", "bereq.method is unset"))?;
             let mut req = Request {
-                method: sob_helper(&bereq.method().unwrap()).to_string(),
+                method: bereq_str(&method, "bereq.method")?.to_string(),
                 url,
-                client: self.client.clone(),
+                client: Client::clone(&self.client),
                 body: None,
-                vcl: false,
+                query: Vec::new(),
+                body_mode: BodyMode::Stream,
+                timeout: None,
+                follow: None,
+                proxy: None,
+                fetch_timeouts: FetchTimeouts::default(),
+                body_sent: None,
                 headers: bereq
                     .into_iter()
                     .map(|(k, v)| (k.into(), v.as_ref().to_owned()))
                     .collect(),
             };
 
-            unsafe {
-                let bo = ctx.raw.bo.as_mut().unwrap();
-                if !bo.bereq_body.is_null()
+            let bo = unsafe { ctx.raw.bo.as_ref() }
+                .ok_or_else(|| ReqError::new("other", "no busyobj, not in a backend fetch"))?;
+            let has_body = unsafe {
+                !bo.bereq_body.is_null()
                     || (!bo.req.is_null() && (*bo.req).req_body_status != BS_NONE.as_ptr())
-                {
-                    let (mut req_body_tx, req_body_rx) =
-                        tokio::sync::mpsc::unbounded_channel::<Result<Bytes, String>>();
-                    req.body = Some(reqwest::Body::wrap_stream(
-                        tokio_stream::wrappers::UnboundedReceiverStream::new(req_body_rx),
-                    ));
-                    // manually dropped a few lines below
-                    let p = (&raw mut req_body_tx).cast::<c_void>();
-
-                    // mimicking V1F_SendReq in varnish-cache
-                    if bo.bereq_body.is_null() {
-                        let i = varnish::ffi::VRB_Iterate(
-                            bo.wrk,
-                            bo.vsl.as_mut_ptr(),
-                            bo.req,
-                            Some(body_send_iterate),
-                            p,
-                        );
+            };
+            if !has_body {
+                return Ok((req, None));
+            }
+            let (body_tx, body_rx) =
+                tokio::sync::mpsc::channel((self.req_body_buffer / REQ_BODY_PIECE).max(1));
+            let (sent_tx, sent_rx) = oneshot::channel();
+            req.body = Some(reqwest::Body::wrap_stream(BereqBody {
+                rx: tokio_stream::wrappers::ReceiverStream::new(body_rx),
+                sent: Some(sent_tx),
+            }));
+            req.body_sent = Some(sent_rx);
+            Ok((req, Some(body_tx)))
+        }
 
-                        if (*bo.req).req_body_status != BS_CACHED.as_ptr() {
-                            bo.no_retry = c"req.body not cached".as_ptr();
-                        }
+        // push the bereq body to the already dispatched request, blocking whenever it has
+        // `req_body_buffer` bytes it hasn't sent to the origin yet
+        fn send_bereq_body(ctx: &mut Ctx<'_>, tx: BodySender) -> Result<(), ReqError> {
+            struct Feed {
+                tx: BodySender,
+                // the request is over (the origin answered early, or failed), stop sending
+                closed: bool,
+            }
 
-                        if (*bo.req).req_body_status == BS_ERROR.as_ptr() {
-                            assert!(i < 0);
-                            (*bo.req).doclose = &raw const varnish::ffi::SC_RX_BODY[0];
+            unsafe extern "C" fn body_send_iterate(
+                priv_: *mut c_void,
+                _flush: c_uint,
+                ptr: *const c_void,
+                l: isize,
+            ) -> i32 {
+                // nothing to do
+                if ptr.is_null() || l <= 0 {
+                    return 0;
+                }
+                let Some(feed) = (unsafe { priv_.cast::<Feed>().as_mut() }) else {
+                    return -1;
+                };
+                #[expect(clippy::cast_sign_loss)]
+                let buf = unsafe { std::slice::from_raw_parts(ptr.cast::<u8>(), l as usize) };
+                // unwinding into C is undefined behavior, fail the fetch instead
+                std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    for piece in buf.chunks(REQ_BODY_PIECE) {
+                        if feed
+                            .tx
+                            .blocking_send(Ok(Bytes::copy_from_slice(piece)))
+                            .is_err()
+                        {
+                            feed.closed = true;
+                            return 1;
                         }
+                    }
+                    0
+                }))
+                .unwrap_or(-1)
+            }
 
-                        if i < 0 {
-                            return Err("req.body read error".into());
-                        }
-                    } else {
-                        varnish::ffi::ObjIterate(
-                            bo.wrk,
-                            bo.bereq_body,
-                            p,
-                            Some(body_send_iterate),
-                            0,
-                        );
+            let mut feed = Feed { tx, closed: false };
+            let p = (&raw mut feed).cast::<c_void>();
+            let bo = unsafe { ctx.raw.bo.as_mut() }
+                .ok_or_else(|| ReqError::new("other", "no busyobj, not in a backend fetch"))?;
+            let i = unsafe {
+                // mimicking V1F_SendReq in varnish-cache
+                if bo.bereq_body.is_null() {
+                    let i = varnish::ffi::VRB_Iterate(
+                        bo.wrk,
+                        bo.vsl.as_mut_ptr(),
+                        bo.req,
+                        Some(body_send_iterate),
+                        p,
+                    );
+
+                    if (*bo.req).req_body_status != BS_CACHED.as_ptr() {
+                        bo.no_retry = c"req.body not cached".as_ptr();
                     }
+
+                    if (*bo.req).req_body_status == BS_ERROR.as_ptr() {
+                        (*bo.req).doclose = &raw const varnish::ffi::SC_RX_BODY[0];
+                    }
+                    i
+                } else {
+                    varnish::ffi::ObjIterate(bo.wrk, bo.bereq_body, p, Some(body_send_iterate), 0)
+                        as isize
                 }
+            };
+            if i < 0 && !feed.closed {
+                // make sure the origin doesn't mistake the truncated body for a full one
+                let _ = feed
+                    .tx
+                    .blocking_send(Err("req.body read error".to_string()));
+                return Err(ReqError::new("req_body", "req.body read error"));
             }
-            let mut resp_rx = unsafe { (*self.bgt).spawn_req(req) };
+            Ok(())
+        }
+    }
 
-            let resp = match resp_rx.blocking_recv().expect("impossible") {
-                RespMsg::Hdrs(resp) => resp,
-                RespMsg::Err(e) => return Err((e.to_string()).into()),
-                RespMsg::Chunk(_) => unreachable!(),
-            };
-            let beresp = ctx.http_beresp.as_mut().unwrap();
-            beresp.set_status(u16::try_from(resp.status).unwrap());
-            beresp.set_proto("HTTP/1.1")?;
-            for (k, v) in &resp.headers {
-                beresp.set_header(
-                    k.as_str(),
-                    v.to_str()
-                        .map_err(|e| <String as Into<VclError>>::into(e.to_string()))?,
-                )?;
-            }
-            Ok(Some(BackendResp {
-                bytes: None,
-                cursor: 0,
-                chan: Some(resp_rx),
-                content_length: resp.content_length.map(|s| usize::try_from(s).unwrap()),
-            }))
+    impl VclBackend<BackendResp> for VCLBackend {
+        fn get_response(&self, ctx: &mut Ctx<'_>) -> VclResult<Option<BackendResp>> {
+            match self.fetch(ctx) {
+                Ok(resp) => {
+                    // a retry succeeded, forget about the previous attempt
+                    FETCH_ERRORS.take(ctx, &self.name);
+                    Ok(resp)
+                }
+                Err(e) => {
+                    let msg = e.msg.clone();
+                    FETCH_ERRORS.insert(ctx, &self.name, e);
+                    Err(msg.into())
+                }
+            }
         }
 
         fn probe(&self, _ctx: &mut Ctx<'_>) -> (bool, SystemTime) {
@@ -192,8 +901,6 @@ pub mod reqwest_private {
                 return (true, SystemTime::UNIX_EPOCH);
             };
 
-            assert!(probe_state.spec.window <= 64);
-
             let bitmap = probe_state.history.load(Ordering::Relaxed);
             (
                 is_healthy(bitmap, probe_state.spec.window, probe_state.spec.threshold),
@@ -208,19 +915,21 @@ pub mod reqwest_private {
             };
 
             // enter the runtime to
-            let _guard = unsafe { (*self.bgt).rt.enter() };
+            let _guard = self.runtime.handle.enter();
             match event {
                 // start the probing loop
                 Event::Warm => {
                     spawn_probe(
-                        unsafe { &*self.bgt },
+                        &self.runtime,
                         std::ptr::from_ref::<ProbeState>(probe_state).cast_mut(),
                         self.name.clone(),
                     );
                 }
                 Event::Cold => {
                     // XXX: we should set the handle to None, but we don't have mutability, oh well...
-                    probe_state.join_handle.as_ref().unwrap().abort();
+                    if let Some(join_handle) = probe_state.join_handle.as_ref() {
+                        join_handle.abort();
+                    }
                 }
                 _ => {}
             }
@@ -238,17 +947,19 @@ pub mod reqwest_private {
                 return;
             };
             let bitmap = history.load(Ordering::Relaxed);
-            vsb.write(&format!(
-                "{}/{}\t{}",
-                good_probes(bitmap, *window),
-                window,
-                if is_healthy(bitmap, *window, *threshold) {
-                    "healthy"
-                } else {
-                    "sick"
-                }
-            ))
-            .expect("vsb buffer full");
+            report_write(
+                vsb,
+                format!(
+                    "{}/{}\t{}",
+                    good_probes(bitmap, *window),
+                    window,
+                    if is_healthy(bitmap, *window, *threshold) {
+                        "healthy"
+                    } else {
+                        "sick"
+                    }
+                ),
+            );
         }
 
         fn report_details(&self, ctx: &mut Ctx<'_>, vsb: &mut Buffer<'_>) {
@@ -262,8 +973,8 @@ pub mod reqwest_private {
             }) = self.probe_state.as_ref()
             else {
                 let state = if self.probe(ctx).0 { "healthy" } else { "sick" };
-                vsb.write(&"0/0\t").expect("vsb buffer full");
-                vsb.write(&state).expect("vsb buffer full");
+                report_write(vsb, "0/0\t");
+                report_write(vsb, state);
                 return;
             };
             let bitmap = history.load(Ordering::Relaxed);
@@ -278,7 +989,7 @@ pub mod reqwest_private {
                 good_probes(bitmap, window),
                 threshold,
                 window,
-                avg.lock().expect("avg mutex poisoned")
+                *avg.lock().unwrap_or_else(PoisonError::into_inner)
             );
             for i in 0..64 {
                 s += if bitmap.wrapping_shr(63 - i) & 1 == 1 {
@@ -287,181 +998,1127 @@ pub mod reqwest_private {
                     "-"
                 };
             }
-            vsb.write(&s).expect("vsb buffer full");
+            report_write(vsb, s);
+        }
+
+        fn report_json(&self, _ctx: &mut Ctx<'_>, vsb: &mut Buffer<'_>) {
+            let Some(ProbeState {
+                history,
+                spec: Probe {
+                    window, threshold, ..
+                },
+                ..
+            }) = self.probe_state.as_ref()
+            else {
+                report_write(vsb, "[]");
+                return;
+            };
+            let bitmap = history.load(Ordering::Relaxed);
+            report_write(
+                vsb,
+                format!(
+                    "[{}, {}, \"{}\"]",
+                    good_probes(bitmap, *window),
+                    window,
+                    if is_healthy(bitmap, *window, *threshold) {
+                        "healthy"
+                    } else {
+                        "sick"
+                    }
+                ),
+            );
+        }
+
+        fn report_details_json(&self, ctx: &mut Ctx<'_>, vsb: &mut Buffer<'_>) {
+            let Some(ref probe_state) = self.probe_state else {
+                let state = if self.probe(ctx).0 { "healthy" } else { "sick" };
+                report_write(vsb, "[0, 0, \"");
+                report_write(vsb, state);
+                report_write(vsb, "\"],");
+                return;
+            };
+            // TODO: talk to upstream, we shouldn't have to add the comma
+            let msg = serde_json::to_string(&probe_state.spec).unwrap_or_else(|_| "{}".to_string())
+                + ",\n";
+            report_write(vsb, msg);
+        }
+    }
+
+    // reports are best-effort, a full vsb only truncates them
+    fn report_write(vsb: &mut Buffer<'_>, s: impl AsRef<[u8]>) {
+        if vsb.write(&s).is_err() {
+            log(
+                LogTag::Error,
+                "reqwest: backend report truncated, vsb is full",
+            );
+        }
+    }
+
+    macro_rules! send {
+        ($tx:ident, $payload:expr) => {
+            if $tx.send($payload).await.is_err() {
+                return;
+            }
+        };
+    }
+
+    pub struct BackendResp {
+        pub chan: Option<Receiver<RespMsg>>,
+        pub drain: Drain,
+        pub content_length: Option<usize>,
+        pub name: String,
+        // still valid when Varnish drops us at the end of the fetch
+        pub bo: *mut busyobj,
+        pub timing: Timing,
+        pub remote_addr: Option<SocketAddr>,
+        // the origin didn't ask to close the connection after this response
+        pub keep_alive: bool,
+    }
+
+    // whether hyper can put the connection back in the pool after `resp`
+    fn keeps_alive(resp: &Response) -> bool {
+        let connection = |token: &str| {
+            resp.headers
+                .get_all(reqwest::header::CONNECTION)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(','))
+                .any(|t| t.trim().eq_ignore_ascii_case(token))
+        };
+        match resp.version {
+            "HTTP/0.9" => false,
+            "HTTP/1.0" => connection("keep-alive"),
+            "HTTP/1.1" => !connection("close"),
+            _ => true,
+        }
+    }
+
+    impl Drop for BackendResp {
+        fn drop(&mut self) {
+            if self.bo.is_null() {
+                return;
+            }
+            if self.drain.done
+                && let Some(body) = self.timing.body.get()
+            {
+                let t = &self.timing;
+                unsafe { timestamp(self.bo, c"Reqwest-Body", t.start + t.first_byte + *body) };
+            }
+            // hyper only puts a connection back in the pool once its body was read to the end, and
+            // if the origin didn't ask to close it
+            let msg = format!(
+                "-1 {} {}",
+                self.name,
+                if self.drain.done && self.keep_alive {
+                    "recycle"
+                } else {
+                    "close"
+                }
+            );
+            unsafe {
+                VSLbt(
+                    (*self.bo).vsl.as_mut_ptr(),
+                    LogTag::BackendClose,
+                    txt::from_str(&msg),
+                );
+            }
+        }
+    }
+
+    impl VclResponse for BackendResp {
+        fn read(&mut self, buf: &mut [u8]) -> VclResult<usize> {
+            let Some(chan) = self.chan.as_mut() else {
+                return Ok(0);
+            };
+            self.drain.read(chan, buf).map_err(|e| e.to_string().into())
+        }
+
+        fn len(&self) -> Option<usize> {
+            self.content_length
+        }
+
+        fn get_ip(&self) -> VclResult<Option<SocketAddr>> {
+            Ok(self.remote_addr)
+        }
+    }
+    #[derive(Debug)]
+    pub enum RespMsg {
+        Hdrs(Box<Response>),
+        Chunk(Bytes),
+        Err(Error),
+    }
+
+    impl From<Bytes> for RespMsg {
+        fn from(bytes: Bytes) -> Self {
+            RespMsg::Chunk(bytes)
+        }
+    }
+
+    impl BodyMsg for RespMsg {
+        type Error = Error;
+
+        fn into_bytes(self) -> Result<Bytes, Error> {
+            match self {
+                RespMsg::Hdrs(_) => Err(anyhow::anyhow!(
+                    "received response headers in the middle of the body"
+                )),
+                RespMsg::Chunk(bytes) => Ok(bytes),
+                RespMsg::Err(e) => Err(e),
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct Entry {
+        pub client_name: String,
+        pub req_name: String,
+        pub transaction: VclTransaction,
+    }
+
+    // try to keep the object on stack as small as possible, we'll flesh it out into a reqwest::Request
+    // once in the Background thread
+    #[derive(Debug)]
+    pub struct Request {
+        pub url: String,
+        pub method: String,
+        pub headers: Vec<(String, Vec<u8>)>,
+        pub query: Vec<(String, String)>,
+        pub body: Option<reqwest::Body>,
+        pub client: Client,
+        pub body_mode: BodyMode,
+        pub timeout: Option<Duration>,
+        // these two can only be applied by swapping `client`, see `client::variant()`
+        pub follow: Option<i64>,
+        pub proxy: Option<String>,
+        pub fetch_timeouts: FetchTimeouts,
+        // fires once a streamed bereq body was handed over in full, `bereq.first_byte_timeout`
+        // only starts then
+        pub body_sent: Option<oneshot::Receiver<()>>,
+    }
+
+    // bereq.connect_timeout, bereq.first_byte_timeout and bereq.between_bytes_timeout, only for
+    // backend fetches
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct FetchTimeouts {
+        pub connect: Option<Duration>,
+        pub first_byte: Option<Duration>,
+        pub between_bytes: Option<Duration>,
+    }
+
+    impl FetchTimeouts {
+        // Varnish leaves them as NAN if the VCL didn't set them, and anything too large for a
+        // Duration is as good as no timeout
+        fn from_bo(bo: &busyobj) -> Self {
+            let get = |t: vtim_dur| {
+                Duration::try_from_secs_f64(t.0)
+                    .ok()
+                    .filter(|d| !d.is_zero())
+            };
+            FetchTimeouts {
+                connect: get(bo.connect_timeout),
+                first_byte: get(bo.first_byte_timeout),
+                between_bytes: get(bo.between_bytes_timeout),
+            }
+        }
+    }
+
+    impl Request {
+        // `duplicate()`, unless the body is a stream that can't be sent twice
+        fn replay(&self) -> Option<Request> {
+            self.body
+                .as_ref()
+                .is_none_or(|b| b.as_bytes().is_some())
+                .then(|| self.duplicate())
+        }
+
+        // only VCL requests can be duplicated, as their bodies are always buffered
+        pub fn duplicate(&self) -> Request {
+            Request {
+                url: self.url.clone(),
+                method: self.method.clone(),
+                headers: self.headers.clone(),
+                query: self.query.clone(),
+                body: self
+                    .body
+                    .as_ref()
+                    .and_then(reqwest::Body::as_bytes)
+                    .map(|b| Vec::from(b).into()),
+                client: self.client.clone(),
+                body_mode: self.body_mode,
+                timeout: self.timeout,
+                follow: self.follow,
+                proxy: self.proxy.clone(),
+                fetch_timeouts: self.fetch_timeouts,
+                body_sent: None,
+            }
+        }
+    }
+
+    // what `init()` can start from, instead of a blank request
+    #[derive(Debug, Default)]
+    pub struct Template {
+        pub method: Option<String>,
+        pub headers: Vec<(String, Vec<u8>)>,
+        pub query: Vec<(String, String)>,
+        pub body: Option<Vec<u8>>,
+    }
+
+    // what the task does with the response body once the headers have been sent over
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum BodyMode {
+        // send it chunk by chunk, as it's consumed
+        Stream,
+        // download it in full right away, and send it as one chunk
+        Buffer,
+        // don't download it at all
+        Discard,
+    }
+
+    impl BodyMode {
+        pub fn parse(mode: &str) -> VclResult<Self> {
+            match mode {
+                "lazy" => Ok(BodyMode::Stream),
+                "buffer" => Ok(BodyMode::Buffer),
+                "discard" => Ok(BodyMode::Discard),
+                _ => Err(format!(
+                    "invalid body_mode ({mode}), expected \"lazy\", \"buffer\" or \"discard\""
+                )
+                .into()),
+            }
+        }
+    }
+
+    // where a request spent its time, `dns` and `connect` stay at 0 if a pooled connection was used
+    #[derive(Debug, Clone)]
+    pub struct Timing {
+        pub start: SystemTime,
+        pub dns: Duration,
+        // TCP connect and TLS handshake, reqwest's OpenSSL backend has no hook between the two
+        pub connect: Duration,
+        // from the start to the response headers
+        pub first_byte: Duration,
+        // from the response headers to the end of the body, set once it's been received in full
+        pub body: Arc<OnceLock<Duration>>,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Phase {
+        Dns,
+        Connect,
+        FirstByte,
+        Body,
+        Total,
+    }
+
+    impl Phase {
+        pub fn parse(phase: &str) -> VclResult<Self> {
+            match phase {
+                "dns" => Ok(Phase::Dns),
+                "connect" => Ok(Phase::Connect),
+                "first_byte" => Ok(Phase::FirstByte),
+                "body" => Ok(Phase::Body),
+                "total" => Ok(Phase::Total),
+                _ => Err(format!(
+                    "invalid phase ({phase}), expected \"dns\", \"connect\", \"first_byte\", \"body\" or \"total\""
+                )
+                .into()),
+            }
+        }
+    }
+
+    impl Timing {
+        pub fn get(&self, phase: Phase) -> Duration {
+            let body = self.body.get().copied().unwrap_or_default();
+            match phase {
+                Phase::Dns => self.dns,
+                Phase::Connect => self.connect,
+                Phase::FirstByte => self.first_byte,
+                Phase::Body => body,
+                Phase::Total => self.first_byte + body,
+            }
+        }
+    }
+
+    use reqwest::header::HeaderMap;
+
+    // calling reqwest::Response::body() consumes the object, so we keep a copy of the interesting bits
+    // in this struct
+    #[derive(Debug)]
+    pub struct Response {
+        pub headers: HeaderMap,
+        pub content_length: Option<u64>,
+        pub body: Option<Bytes>,
+        pub status: i64,
+        pub url: String,
+        pub redirects: usize,
+        pub version: &'static str,
+        pub remote_addr: Option<SocketAddr>,
+        pub local_addr: Option<SocketAddr>,
+        pub connected: bool,
+        pub timing: Timing,
+        // the DER of the server certificate, only for HTTPS and only parsed if asked for, reqwest
+        // doesn't tell us the TLS version or cipher
+        pub peer_cert: Option<Vec<u8>>,
+        // the body chunks still in flight, for VCL requests
+        rest: Option<Receiver<RespMsg>>,
+    }
+
+    impl Response {
+        // pull the rest of the body into `body`, if it hasn't been already
+        fn load_body(&mut self) -> Result<(), ReqError> {
+            let Some(mut rx) = self.rest.take() else {
+                return Ok(());
+            };
+            let mut body = BytesMut::new();
+            while let Some(msg) = rx.blocking_recv() {
+                match msg {
+                    RespMsg::Chunk(bytes) => body.extend_from_slice(&bytes),
+                    RespMsg::Err(e) => return Err(ReqError::with_cause(&e)),
+                    RespMsg::Hdrs(_) => {
+                        return Err(ReqError::new(
+                            "other",
+                            "received response headers in the middle of the body",
+                        ));
+                    }
+                }
+            }
+            self.body = Some(body.freeze());
+            Ok(())
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum VclTransaction {
+        Transition,
+        Req(Request),
+        Sent(Pending),
+        Resp(Result<Response, ReqError>),
+        // left behind in the client task once the request is handed off to the backend one, only
+        // kept for its `Drop`
+        Transferred(#[expect(dead_code)] HandOff),
+    }
+
+    // requests waiting to be adopted by a backend task, keyed by the vxid of the task they come
+    // from, then by client and request names, no request name meaning it's the backend fetch
+    // itself (see `backend_for()`)
+    type HandOffKey = (u64, String, Option<String>);
+    static HANDOFFS: LazyLock<Mutex<HashMap<HandOffKey, VclTransaction>>> =
+        LazyLock::new(Mutex::default);
+
+    // dropping it at the end of the client task reclaims the request if no backend task took it
+    #[derive(Debug)]
+    pub struct HandOff(HandOffKey);
+
+    impl Drop for HandOff {
+        fn drop(&mut self) {
+            let t = HANDOFFS
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove(&self.0);
+            // drop the transaction outside of the lock
+            drop(t);
+        }
+    }
+
+    // values attached to a backend task, keyed by client name. They live in the task's PRIV_TASK
+    // storage, and are dropped along with the busyobj, without any global state.
+    pub struct TaskMap<V> {
+        // also identifies the map among the other PRIV_TASK entries
+        methods: vmod_priv_methods,
+        _values: PhantomData<V>,
+    }
+
+    impl<V> TaskMap<V> {
+        const fn new(name: &'static CStr) -> Self {
+            TaskMap {
+                methods: vmod_priv_methods {
+                    magic: VMOD_PRIV_METHODS_MAGIC,
+                    type_: name.as_ptr(),
+                    fini: Some(vmod_priv::on_fini::<HashMap<String, V>>),
+                },
+                _values: PhantomData,
+            }
+        }
+
+        // run `f` on the map of the current backend task, `None` outside of one (or if the
+        // workspace is exhausted)
+        fn with<T>(
+            &'static self,
+            ctx: &Ctx,
+            f: impl FnOnce(&mut HashMap<String, V>) -> T,
+        ) -> Option<T> {
+            if ctx.raw.bo.is_null() {
+                return None;
+            }
+            unsafe {
+                let vp = VRT_priv_task(&raw const *ctx.raw, (&raw const *self).cast()).as_mut()?;
+                if vp.priv_.is_null() {
+                    vp.put(Box::new(HashMap::<String, V>::new()), &self.methods);
+                }
+                vp.priv_.cast::<HashMap<String, V>>().as_mut().map(f)
+            }
+        }
+
+        // returns false outside of a backend task
+        fn insert(&'static self, ctx: &Ctx, client_name: &str, v: V) -> bool {
+            self.with(ctx, |map| map.insert(client_name.to_owned(), v))
+                .is_some()
+        }
+
+        fn take(&'static self, ctx: &Ctx, client_name: &str) -> Option<V> {
+            self.with(ctx, |map| map.remove(client_name))?
+        }
+    }
+
+    impl<V: Clone> TaskMap<V> {
+        fn get(&'static self, ctx: &Ctx, client_name: &str) -> Option<V> {
+            self.with(ctx, |map| map.get(client_name).cloned())?
+        }
+    }
+
+    // base_url overrides set by `backend()`, consumed by the fetch
+    static BASE_URLS: TaskMap<String> = TaskMap::new(c"reqwest base_url overrides");
+
+    pub fn set_base_url(ctx: &Ctx, client_name: &str, base_url: &str) -> VclResult<()> {
+        let url = Url::parse(base_url).map_err(|e| format!("invalid base_url {base_url} ({e})"))?;
+        if !matches!(url.scheme(), "http" | "https") || !url.has_host() {
+            return Err(format!(
+                "invalid base_url {base_url} (needs an http(s) scheme and a host)"
+            )
+            .into());
+        }
+        if !BASE_URLS.insert(ctx, client_name, base_url.to_owned()) {
+            return Err("a base_url can only be specified in backend subroutines".into());
+        }
+        Ok(())
+    }
+
+    // why the last fetch of a backend task failed, for `backend_error()`
+    static FETCH_ERRORS: TaskMap<ReqError> = TaskMap::new(c"reqwest fetch errors");
+
+    // why a request (VCL or backend one) failed
+    #[derive(Debug, Clone)]
+    pub struct ReqError {
+        // one of the stable names returned by `error_kind()`, or a backend-specific one
+        pub kind: &'static str,
+        pub msg: String,
+    }
+
+    impl ReqError {
+        fn new(kind: &'static str, msg: impl Into<String>) -> Self {
+            ReqError {
+                kind,
+                msg: msg.into(),
+            }
+        }
+
+        pub fn get(ctx: &Ctx, client_name: &str) -> Option<Self> {
+            FETCH_ERRORS.get(ctx, client_name)
+        }
+
+        // VCL requests also report the root cause, which is usually the interesting part
+        fn with_cause(e: &Error) -> Self {
+            ReqError::new(error_kind(e), format!("{e}: {}", e.root_cause()))
+        }
+
+        pub fn is_timeout(&self) -> bool {
+            self.kind == "timeout"
+        }
+    }
+
+    impl From<VclError> for ReqError {
+        fn from(e: VclError) -> Self {
+            ReqError::new("other", e.to_string())
+        }
+    }
+
+    impl From<Error> for ReqError {
+        fn from(e: Error) -> Self {
+            ReqError::new(error_kind(&e), e.to_string())
+        }
+    }
+
+    impl std::fmt::Display for ReqError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(&self.msg)
+        }
+    }
+
+    // `e` and its sources, `io::Error::source()` skips the error it wraps, so we look into those
+    fn causes(e: &Error) -> Vec<&(dyn std::error::Error + 'static)> {
+        let mut causes = Vec::new();
+        let mut next: Option<&(dyn std::error::Error + 'static)> = Some(e.as_ref());
+        while let Some(cause) = next {
+            causes.push(cause);
+            next = match cause
+                .downcast_ref::<std::io::Error>()
+                .and_then(std::io::Error::get_ref)
+            {
+                Some(inner) => Some(inner),
+                None => cause.source(),
+            };
+        }
+        causes
+    }
+
+    // a stable name for what went wrong, that VCL can rely on
+    pub fn error_kind(e: &Error) -> &'static str {
+        let mut reqwest_error = None;
+        for cause in causes(e) {
+            if cause.is::<tokio::time::error::Elapsed>()
+                || cause
+                    .downcast_ref::<std::io::Error>()
+                    .is_some_and(|e| e.kind() == std::io::ErrorKind::TimedOut)
+            {
+                return "timeout";
+            } else if cause.is::<DnsError>() {
+                return "dns";
+            } else if cause.is::<native_tls::Error>() {
+                return "tls";
+            } else if cause.is::<InvalidRequest>() {
+                return "builder";
+            } else if cause.is::<PrivateRedirect>() {
+                return "redirect";
+            }
+            reqwest_error = reqwest_error.or_else(|| cause.downcast_ref::<reqwest::Error>());
+        }
+        let Some(e) = reqwest_error else {
+            return "other";
+        };
+        if e.is_timeout() {
+            "timeout"
+        } else if e.is_connect() {
+            "connect"
+        } else if e.is_redirect() {
+            "redirect"
+        } else if e.is_body() {
+            "body"
+        } else if e.is_decode() {
+            "decode"
+        } else if e.is_builder() {
+            "builder"
+        } else {
+            "other"
+        }
+    }
+
+    // a copy of the last request prepared by `backend_for()`, for `return (retry)` to send it
+    // again, `None` if it can't be
+    static REPLAYS: TaskMap<Option<Request>> = TaskMap::new(c"reqwest backend_for replays");
+
+    // the request prepared by `backend_for()` for this fetch, `Ok(None)` if there's none and
+    // `bereq` should be used
+    fn prepared_fetch(ctx: &Ctx, client_name: &str) -> Result<Option<Request>, ReqError> {
+        let req = match take_fetch(ctx, client_name) {
+            Some(req) => req,
+            None => match REPLAYS.take(ctx, client_name) {
+                None => return Ok(None),
+                Some(None) => {
+                    return Err(ReqError::new(
+                        "other",
+                        "the request prepared by backend_for() can't be sent again",
+                    ));
+                }
+                Some(Some(req)) => req,
+            },
+        };
+        REPLAYS.insert(ctx, client_name, req.replay());
+        Ok(Some(req))
+    }
+
+    // find the request prepared by `backend_for()`, either in this backend task, or in the client
+    // one (which we can only reach until the request body has been sent)
+    fn take_fetch(ctx: &Ctx, client_name: &str) -> Option<Request> {
+        let bo = unsafe { ctx.raw.bo.as_ref()? };
+        let mut vxids = vec![bo.vsl[0].wid.vxid];
+        if let Some(req) = unsafe { bo.req.as_ref() } {
+            vxids.push(req.vsl[0].wid.vxid);
+        }
+        let mut handoffs = HANDOFFS.lock().unwrap_or_else(PoisonError::into_inner);
+        vxids.into_iter().find_map(|vxid| {
+            // only unsent requests are stored under this key, see `prepare_fetch()`
+            match handoffs.remove(&(vxid, client_name.to_owned(), None))? {
+                VclTransaction::Req(req) => Some(req),
+                _ => None,
+            }
+        })
+    }
+
+    #[derive(Debug)]
+    pub struct Pending {
+        pub rx: Receiver<RespMsg>,
+        // lets a fire-and-forget request skip the line once somebody actually waits on it
+        promote: Option<Arc<Notify>>,
+        cancel: Canceller,
+    }
+
+    impl Pending {
+        fn promote(&self) {
+            if let Some(promote) = self.promote.as_ref() {
+                promote.notify_one();
+            }
+        }
+    }
+
+    // dropping the sender without using it lets the request run to completion, unless `on_drop`
+    // is set
+    #[derive(Debug)]
+    struct Canceller {
+        tx: Option<oneshot::Sender<()>>,
+        on_drop: bool,
+    }
+
+    impl Canceller {
+        fn cancel(&mut self) {
+            if let Some(tx) = self.tx.take() {
+                let _ = tx.send(());
+            }
+        }
+    }
+
+    impl Drop for Canceller {
+        fn drop(&mut self) {
+            if self.on_drop {
+                self.cancel();
+            }
+        }
+    }
+
+    impl VclTransaction {
+        fn unwrap_resp(&self) -> VclResult<Result<&Response, &ReqError>> {
+            match self {
+                VclTransaction::Resp(Ok(rsp)) => Ok(Ok(rsp)),
+                VclTransaction::Resp(Err(e)) => Ok(Err(e)),
+                _ => Err(self.unexpected()),
+            }
         }
-
-        fn report_json(&self, _ctx: &mut Ctx<'_>, vsb: &mut Buffer<'_>) {
-            let Some(ProbeState {
-                history,
-                spec: Probe {
-                    window, threshold, ..
-                },
-                ..
-            }) = self.probe_state.as_ref()
-            else {
-                vsb.write(&"[]").expect("vsb buffer full");
-                return;
-            };
-            let bitmap = history.load(Ordering::Relaxed);
-            vsb.write(&format!(
-                "[{}, {}, \"{}\"]",
-                good_probes(bitmap, *window),
-                window,
-                if is_healthy(bitmap, *window, *threshold) {
-                    "healthy"
-                } else {
-                    "sick"
+        // leaves `self` in transition, unless it isn't an unsent request
+        fn take_req(&mut self) -> VclResult<Request> {
+            match std::mem::replace(self, VclTransaction::Transition) {
+                VclTransaction::Req(rq) => Ok(rq),
+                old => {
+                    let e = old.unexpected();
+                    *self = old;
+                    Err(e)
                 }
-            ))
-            .expect("vsb buffer full");
+            }
         }
-
-        fn report_details_json(&self, ctx: &mut Ctx<'_>, vsb: &mut Buffer<'_>) {
-            let Some(ref probe_state) = self.probe_state else {
-                let state = if self.probe(ctx).0 { "healthy" } else { "sick" };
-                vsb.write(&"[0, 0, \"").expect("vsb buffer full");
-                vsb.write(&state).expect("vsb buffer full");
-                vsb.write(&"\"],").expect("vsb buffer full");
-                return;
+        fn unexpected(&self) -> VclError {
+            let state = match self {
+                VclTransaction::Transition => "in transition",
+                VclTransaction::Req(_) => "unsent",
+                VclTransaction::Sent(_) => "in flight",
+                VclTransaction::Resp(_) => "complete",
+                VclTransaction::Transferred(_) => "transferred",
             };
-            // TODO: talk to upstream, we shouldn't have to add the comma
-            let msg = serde_json::to_string(&probe_state.spec)
-                .expect("probe spec serialization failed")
-                + ",\n";
-            vsb.write(&msg).expect("vsb buffer full");
+            VclError::new(format!("reqwest: unexpected request state ({state})"))
         }
     }
 
-    macro_rules! send {
-        ($tx:ident, $payload:expr) => {
-            if $tx.send($payload).await.is_err() {
-                return;
+    // how many fire-and-forget requests run at once when `configure_runtime()` doesn't say, past
+    // that, the requests somebody waits on get ahead of them
+    const DEFAULT_DETACHED_CONCURRENCY: usize = 256;
+
+    #[derive(Debug, Default, Clone, PartialEq, Eq)]
+    pub struct RuntimeSettings {
+        pub worker_threads: Option<usize>,
+        pub thread_name: Option<String>,
+        pub max_blocking_threads: Option<usize>,
+        pub current_thread: bool,
+        pub detached_concurrency: Option<usize>,
+    }
+
+    struct Job {
+        req: Request,
+        tx: Sender<RespMsg>,
+        inflight: InFlight,
+        // only set for detached requests
+        promote: Option<Arc<Notify>>,
+        cancel: oneshot::Receiver<()>,
+    }
+
+    impl Job {
+        async fn run(self, budget: Arc<Semaphore>) {
+            let Job {
+                req,
+                tx,
+                inflight,
+                promote,
+                cancel,
+            } = self;
+            let mut abandon = inflight.tracker.abandon.subscribe();
+            tokio::select! {
+                () = async {
+                    // detached requests must fit in the budget, unless somebody starts waiting on them
+                    let _permit = match promote {
+                        Some(promote) => tokio::select! {
+                            biased;
+                            () = promote.notified() => None,
+                            permit = budget.acquire_owned() => permit.ok(),
+                        },
+                        None => None,
+                    };
+                    process_req(req, tx).await;
+                } => {}
+                _ = abandon.wait_for(|abandoned| *abandoned) => {}
+                // an error means nobody will cancel us, and the branch is simply disabled
+                Ok(()) = cancel => {}
             }
-        };
+            drop(inflight);
+        }
     }
 
-    pub struct BackendResp {
-        pub chan: Option<Receiver<RespMsg>>,
-        pub bytes: Option<Bytes>,
-        pub cursor: usize,
-        pub content_length: Option<usize>,
+    pub struct BgRuntime {
+        pub handle: tokio::runtime::Handle,
+        // None for current-thread runtimes, they are owned (and driven) by their own thread
+        _rt: Option<tokio::runtime::Runtime>,
+        // dropping it stops the thread driving a current-thread runtime
+        _stop: Option<oneshot::Sender<()>>,
+        // fire-and-forget requests need one of its permits to start, until somebody waits on them
+        detached_budget: Arc<Semaphore>,
     }
 
-    impl VclResponse for BackendResp {
-        fn read(&mut self, mut buf: &mut [u8]) -> VclResult<usize> {
-            let mut n = 0;
-            loop {
-                if self.bytes.is_none()
-                    && let Some(chan) = self.chan.as_mut()
-                {
-                    match chan.blocking_recv() {
-                        Some(RespMsg::Hdrs(_)) => panic!("invalid message type: RespMsg::Hdrs"),
-                        Some(RespMsg::Chunk(bytes)) => {
-                            self.bytes = Some(bytes);
-                            self.cursor = 0;
-                        }
-                        Some(RespMsg::Err(e)) => return Err(e.to_string().into()),
-                        None => return Ok(n),
-                    }
-                }
-                let pull_buf = self.bytes.as_ref().unwrap();
-                let to_write = &pull_buf[self.cursor..];
-                let used = buf.write(to_write).unwrap();
-                self.cursor += used;
-                n += used;
-                assert!(self.cursor <= pull_buf.len());
-                if self.cursor == pull_buf.len() {
-                    self.bytes = None;
-                }
-                if buf.is_empty() {
-                    return Ok(n);
-                }
+    impl BgRuntime {
+        fn new(settings: &RuntimeSettings) -> std::io::Result<Self> {
+            let mut builder = if settings.current_thread {
+                tokio::runtime::Builder::new_current_thread()
+            } else {
+                tokio::runtime::Builder::new_multi_thread()
+            };
+            builder.enable_all();
+            if let Some(n) = settings.worker_threads {
+                builder.worker_threads(n);
+            }
+            if let Some(n) = settings.max_blocking_threads {
+                builder.max_blocking_threads(n);
+            }
+            if let Some(prefix) = settings.thread_name.clone() {
+                let id = AtomicUsize::new(0);
+                builder.thread_name_fn(move || {
+                    format!("{prefix}-{}", id.fetch_add(1, Ordering::Relaxed))
+                });
             }
+            let rt = builder.build()?;
+            let handle = rt.handle().clone();
+            let (rt, stop) = if settings.current_thread {
+                // a current-thread runtime only makes progress while something is blocked on it
+                let (stop, stopped) = oneshot::channel::<()>();
+                let mut thread = std::thread::Builder::new();
+                if let Some(prefix) = &settings.thread_name {
+                    thread = thread.name(prefix.clone());
+                }
+                thread.spawn(move || {
+                    let _ = rt.block_on(stopped);
+                })?;
+                (None, Some(stop))
+            } else {
+                (Some(rt), None)
+            };
+            Ok(BgRuntime {
+                handle,
+                _rt: rt,
+                _stop: stop,
+                detached_budget: Arc::new(Semaphore::new(
+                    settings
+                        .detached_concurrency
+                        .unwrap_or(DEFAULT_DETACHED_CONCURRENCY),
+                )),
+            })
         }
+    }
 
-        fn len(&self) -> Option<usize> {
-            self.content_length
+    // keeps track of the requests of a VCL that are still in flight, so that we can give them a
+    // chance to finish when the VCL goes cold
+    #[derive(Default)]
+    struct Tracker {
+        cold: AtomicBool,
+        next_id: AtomicU64,
+        requests: Mutex<HashMap<u64, String>>,
+        drained: Condvar,
+        abandon: watch::Sender<bool>,
+        // bumped on every temperature change, so that a drain outlived by its cold period gives up
+        generation: AtomicU64,
+    }
+
+    impl Tracker {
+        fn enter(self: &Arc<Self>, req: &Request) -> VclResult<InFlight> {
+            if self.cold.load(Ordering::Relaxed) {
+                return Err("the VCL is cold, not accepting new requests".into());
+            }
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            self.requests
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(id, format!("{} {}", req.method, req.url));
+            Ok(InFlight {
+                tracker: self.clone(),
+                id,
+            })
         }
     }
-    #[derive(Debug)]
-    pub enum RespMsg {
-        Hdrs(Response),
-        Chunk(Bytes),
-        Err(Error),
+
+    pub struct InFlight {
+        tracker: Arc<Tracker>,
+        id: u64,
     }
 
-    #[derive(Debug)]
-    pub struct Entry {
-        pub client_name: String,
-        pub req_name: String,
-        pub transaction: VclTransaction,
+    impl Drop for InFlight {
+        fn drop(&mut self) {
+            let mut requests = self
+                .tracker
+                .requests
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            requests.remove(&self.id);
+            if requests.is_empty() {
+                self.tracker.drained.notify_all();
+            }
+        }
     }
 
-    // try to keep the object on stack as small as possible, we'll flesh it out into a reqwest::Request
-    // once in the Background thread
-    #[derive(Debug)]
-    pub struct Request {
-        pub url: String,
-        pub method: String,
-        pub headers: Vec<(String, Vec<u8>)>,
-        pub body: Option<reqwest::Body>,
-        pub client: Client,
-        pub vcl: bool,
+    const DEFAULT_DRAIN_GRACE: Duration = Duration::from_secs(5);
+
+    // there's only one default runtime per process, so that reloading a VCL doesn't spin up a new
+    // set of threads, plus the named ones requested by clients. The first user of a runtime decides
+    // its settings.
+    type Runtimes = HashMap<String, (RuntimeSettings, Arc<BgRuntime>)>;
+    static RUNTIMES: LazyLock<Mutex<Runtimes>> = LazyLock::new(Mutex::default);
+
+    // `name` is empty for the default runtime, `settings` is None if the caller doesn't care about
+    // them
+    pub fn get_runtime(name: &str, settings: Option<RuntimeSettings>) -> VclResult<Arc<BgRuntime>> {
+        let mut runtimes = RUNTIMES.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((running, rt)) = runtimes.get(name) {
+            if settings.is_some_and(|s| s != *running) {
+                let what = if name.is_empty() {
+                    "the runtime".to_string()
+                } else {
+                    format!("runtime {name}")
+                };
+                return Err(VclError::new(format!(
+                    "{what} is already running with different settings ({running:?}), varnishd needs to be restarted to change them"
+                )));
+            }
+            return Ok(rt.clone());
+        }
+        let settings = settings.unwrap_or_default();
+        let rt = Arc::new(
+            BgRuntime::new(&settings)
+                .map_err(|e| VclError::new(format!("couldn't start the runtime ({e})")))?,
+        );
+        runtimes.insert(name.to_owned(), (settings, rt.clone()));
+        Ok(rt)
     }
 
-    use reqwest::header::HeaderMap;
+    // the runtime is only started once the first client needs it, so that `configure_runtime()`
+    // can still tweak it during `vcl_init`
+    #[derive(Default)]
+    pub struct BgThread {
+        settings: Mutex<Option<RuntimeSettings>>,
+        runtime: OnceLock<Arc<BgRuntime>>,
+        tracker: Arc<Tracker>,
+        drain_grace: Mutex<Option<Duration>>,
+    }
 
-    // calling reqwest::Response::body() consumes the object, so we keep a copy of the interesting bits
-    // in this struct
-    #[derive(Debug)]
-    pub struct Response {
-        pub headers: HeaderMap,
-        pub content_length: Option<u64>,
-        pub body: Option<Bytes>,
-        pub status: i64,
+    // the per-VCL state is set up by the event handler before any VCL code runs, but be defensive
+    // rather than take the worker down
+    pub fn bg_thread(vp_vcl: Option<&BgThread>) -> VclResult<&BgThread> {
+        vp_vcl.ok_or_else(|| {
+            VclError::new("reqwest: no per-VCL state, was the vmod loaded properly?".to_string())
+        })
     }
 
-    #[derive(Debug)]
-    pub enum VclTransaction {
-        Transition,
-        Req(Request),
-        Sent(Receiver<RespMsg>),
-        Resp(Result<Response, VclError>),
+    impl Drop for BgThread {
+        fn drop(&mut self) {
+            // the runtime outlives us, make sure nothing keeps running on behalf of a discarded VCL
+            self.tracker.abandon.send_replace(true);
+        }
     }
 
-    impl VclTransaction {
-        fn unwrap_resp(&self) -> Result<&Response, VclError> {
-            match self {
-                VclTransaction::Resp(Ok(rsp)) => Ok(rsp),
-                VclTransaction::Resp(Err(e)) => Err(VclError::new(e.to_string())),
-                _ => panic!("wrong VclTransaction type"),
+    impl BgThread {
+        pub fn configure(&self, settings: RuntimeSettings) -> VclResult<()> {
+            if self.runtime.get().is_some() {
+                return Err(
+                    "the runtime is already running, configure_runtime() must be called before creating any client"
+                        .into(),
+                );
             }
+            *self.settings.lock().unwrap_or_else(PoisonError::into_inner) = Some(settings);
+            Ok(())
         }
-        fn into_req(self) -> Request {
-            match self {
-                VclTransaction::Req(rq) => rq,
-                _ => panic!("wrong VclTransaction type"),
+
+        pub fn runtime(&self) -> VclResult<Arc<BgRuntime>> {
+            if let Some(rt) = self.runtime.get() {
+                return Ok(rt.clone());
+            }
+            let settings = self
+                .settings
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone();
+            let rt = get_runtime("", settings)?;
+            Ok(self.runtime.get_or_init(|| rt).clone())
+        }
+
+        pub fn set_drain_grace(&self, grace: Duration) {
+            *self
+                .drain_grace
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = Some(grace);
+        }
+
+        // stop accepting new requests, and give the in-flight ones some time to complete before
+        // abandoning them. Events run on the CLI thread, which must not block for anywhere near
+        // `cli_timeout`, so the waiting happens on a thread of its own.
+        pub fn drain(&self) {
+            let tracker = self.tracker.clone();
+            tracker.cold.store(true, Ordering::Relaxed);
+            let generation = {
+                let _requests = tracker
+                    .requests
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                tracker.generation.fetch_add(1, Ordering::Relaxed) + 1
+            };
+            let grace = self
+                .drain_grace
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .unwrap_or(DEFAULT_DRAIN_GRACE);
+            let spawned = std::thread::Builder::new()
+                .name("reqwest-drain".to_string())
+                .spawn(move || {
+                    let (requests, _) = tracker
+                        .drained
+                        .wait_timeout_while(
+                            tracker
+                                .requests
+                                .lock()
+                                .unwrap_or_else(PoisonError::into_inner),
+                            grace,
+                            |r| {
+                                !r.is_empty()
+                                    && tracker.generation.load(Ordering::Relaxed) == generation
+                            },
+                        )
+                        .unwrap_or_else(PoisonError::into_inner);
+                    // empty, or warm again
+                    if requests.is_empty()
+                        || tracker.generation.load(Ordering::Relaxed) != generation
+                    {
+                        return;
+                    }
+                    for desc in requests.values() {
+                        log(
+                            LogTag::Error,
+                            format!("reqwest: abandoning in-flight request ({desc})"),
+                        );
+                    }
+                    drop(requests);
+                    tracker.abandon.send_replace(true);
+                });
+            if let Err(e) = spawned {
+                log(
+                    LogTag::Error,
+                    format!(
+                        "reqwest: couldn't start the drain thread, abandoning in-flight requests ({e})"
+                    ),
+                );
+                self.tracker.abandon.send_replace(true);
+            }
+        }
+
+        pub fn resume(&self) {
+            let tracker = &self.tracker;
+            {
+                // under the lock, so that a draining thread can't miss the wake-up
+                let _requests = tracker
+                    .requests
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                tracker.generation.fetch_add(1, Ordering::Relaxed);
+                tracker.drained.notify_all();
             }
+            tracker.abandon.send_replace(false);
+            tracker.cold.store(false, Ordering::Relaxed);
+        }
+
+        fn spawn_req(&self, rt: &BgRuntime, req: Request, urgent: bool) -> VclResult<Pending> {
+            let inflight = self.tracker.enter(&req)?;
+            let (tx, rx) = tokio::sync::mpsc::channel(relay::PIPELINE_DEPTH);
+            let promote = (!urgent).then(|| Arc::new(Notify::new()));
+            let (cancel_tx, cancel_rx) = oneshot::channel();
+            let job = Job {
+                req,
+                tx,
+                inflight,
+                promote: promote.clone(),
+                cancel: cancel_rx,
+            };
+            rt.handle.spawn(job.run(rt.detached_budget.clone()));
+            Ok(Pending {
+                rx,
+                promote,
+                cancel: Canceller {
+                    tx: Some(cancel_tx),
+                    on_drop: false,
+                },
+            })
         }
     }
 
-    pub struct BgThread {
-        pub rt: tokio::runtime::Runtime,
-        pub sender: UnboundedSender<(Request, Sender<RespMsg>)>,
+    // when to stop waiting `timeout` from now, `None` (never) if that's too far to represent
+    pub fn deadline(timeout: Duration) -> Option<tokio::time::Instant> {
+        tokio::time::Instant::now().checked_add(timeout)
     }
 
-    impl BgThread {
-        fn spawn_req(&self, req: Request) -> Receiver<RespMsg> {
-            let (tx, rx) = tokio::sync::mpsc::channel(1);
-            self.sender.send((req, tx)).unwrap();
-            rx
+    // await `fut`, giving up at `deadline` if there's one. The timer is only created once polled,
+    // i.e. inside the runtime.
+    async fn until<F: Future>(deadline: Option<tokio::time::Instant>, fut: F) -> Option<F::Output> {
+        match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, fut).await.ok(),
+            None => Some(fut.await),
+        }
+    }
+
+    // await `fut`, failing with `what` if a `timeout` is given and runs out first
+    async fn within<T, E: Into<Error>>(
+        timeout: Option<Duration>,
+        what: &'static str,
+        fut: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, Error> {
+        match timeout {
+            None => fut.await.map_err(Into::into),
+            Some(timeout) => tokio::time::timeout(timeout, fut)
+                .await
+                .context(what)?
+                .map_err(Into::into),
+        }
+    }
+
+    // `within()` for the response headers, but if the request has a streamed body, the clock
+    // only starts once it was sent, as with native backends
+    async fn first_byte<T, E: Into<Error>>(
+        timeout: Option<Duration>,
+        body_sent: Option<oneshot::Receiver<()>>,
+        fut: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, Error> {
+        let mut fut = std::pin::pin!(fut);
+        if let (Some(_), Some(body_sent)) = (timeout, body_sent) {
+            tokio::select! {
+                res = &mut fut => return res.map_err(Into::into),
+                // the body going away without finishing starts the clock too
+                _ = body_sent => {}
+            }
         }
+        within(timeout, "first byte timeout", fut).await
     }
 
     pub async fn process_req(req: Request, tx: Sender<RespMsg>) {
         let method = match reqwest::Method::from_bytes(req.method.as_bytes()) {
             Ok(m) => m,
             Err(e) => {
+                let e = InvalidRequest(format!("{e} ({})", req.method));
                 send!(tx, RespMsg::Err(e.into()));
                 return;
             }
@@ -470,54 +2127,115 @@ pub mod reqwest_private {
         for (k, v) in req.headers {
             rreq = rreq.header(k, v);
         }
+        if !req.query.is_empty() {
+            rreq = rreq.query(&req.query);
+        }
+        if let Some(timeout) = req.timeout {
+            rreq = rreq.timeout(timeout);
+        }
         if let Some(body) = req.body {
             rreq = rreq.body(body);
         }
-        let mut resp = match rreq.send().await {
+        let start = SystemTime::now();
+        let started = Instant::now();
+        let trace = Trace {
+            connect_timeout: req.fetch_timeouts.connect,
+            ..Trace::default()
+        };
+        let (resp, trace) = TRACE
+            .scope(trace, async {
+                let resp =
+                    first_byte(req.fetch_timeouts.first_byte, req.body_sent, rreq.send()).await;
+                (resp, TRACE.with(Trace::clone))
+            })
+            .await;
+        let mut resp = match resp {
             Err(e) => {
-                send!(tx, RespMsg::Err(e.into()));
+                send!(tx, RespMsg::Err(e));
                 return;
             }
             Ok(resp) => resp,
         };
-        let mut beresp = Response {
+        let beresp = Response {
             status: i64::from(resp.status().as_u16()),
             headers: resp.headers().clone(),
             content_length: resp.content_length(),
+            url: resp.url().to_string(),
+            redirects: trace.redirects.get(),
+            connected: trace.connected.get(),
+            timing: Timing {
+                start,
+                dns: trace.dns.get(),
+                connect: trace.connect.get().saturating_sub(trace.dns.get()),
+                first_byte: started.elapsed(),
+                body: Arc::default(),
+            },
+            version: http_version(resp.version()),
+            remote_addr: resp.remote_addr(),
+            local_addr: resp
+                .extensions()
+                .get::<HttpInfo>()
+                .map(HttpInfo::local_addr),
+            peer_cert: resp
+                .extensions()
+                .get::<reqwest::tls::TlsInfo>()
+                .and_then(reqwest::tls::TlsInfo::peer_certificate)
+                .map(<[u8]>::to_vec),
             body: None,
+            rest: None,
         };
+        let body_time = beresp.timing.body.clone();
+        let body_start = Instant::now();
+        send!(tx, RespMsg::Hdrs(Box::new(beresp)));
 
-        if req.vcl {
-            beresp.body = match resp.bytes().await {
-                Err(e) => {
-                    send!(tx, RespMsg::Err(e.into()));
-                    return;
+        match req.body_mode {
+            BodyMode::Discard => {}
+            BodyMode::Buffer => match resp.bytes().await {
+                Ok(bytes) => {
+                    let _ = body_time.set(body_start.elapsed());
+                    send!(tx, RespMsg::Chunk(bytes));
                 }
-                Ok(b) => Some(b),
-            };
-            send!(tx, RespMsg::Hdrs(beresp));
-        } else {
-            send!(tx, RespMsg::Hdrs(beresp));
-
-            loop {
-                match resp.chunk().await {
-                    Ok(None) => return,
-                    Ok(Some(bytes)) => {
-                        if tx.send(RespMsg::Chunk(bytes)).await.is_err() {
-                            return;
-                        }
-                    }
-                    Err(e) => {
-                        send!(tx, RespMsg::Err(e.into()));
-                        return;
+                Err(e) => send!(tx, RespMsg::Err(e.into())),
+            },
+            BodyMode::Stream => {
+                let mut body = TimedBody {
+                    resp: &mut resp,
+                    between_bytes: req.fetch_timeouts.between_bytes,
+                };
+                match relay::pump(&mut body, &tx).await {
+                    Ok(true) => {
+                        let _ = body_time.set(body_start.elapsed());
                     }
+                    Ok(false) => {}
+                    Err(e) => send!(tx, RespMsg::Err(e)),
                 }
             }
         }
     }
 
+    // the response body, one chunk at a time
+    struct TimedBody<'a> {
+        resp: &'a mut reqwest::Response,
+        between_bytes: Option<Duration>,
+    }
+
+    impl ChunkSource for TimedBody<'_> {
+        type Error = Error;
+
+        fn chunk(&mut self) -> impl Future<Output = Result<Option<Bytes>, Error>> {
+            within(
+                self.between_bytes,
+                "between bytes timeout",
+                self.resp.chunk(),
+            )
+        }
+    }
+
     fn good_probes(bitmap: u64, window: u32) -> u32 {
-        bitmap.wrapping_shl(64_u32 - window).count_ones()
+        // varnish caps .window at 64, the size of the history
+        bitmap
+            .wrapping_shl(64_u32.saturating_sub(window))
+            .count_ones()
     }
 
     fn is_healthy(bitmap: u64, window: u32, threshold: u32) -> bool {
@@ -539,13 +2257,15 @@ pub mod reqwest_private {
 
     // cheating hard with the pointer here, but the be_event function will stop us
     // before the references are invalid
-    fn spawn_probe(bgt: &'static BgThread, probe_state: *mut ProbeState, name: String) {
-        let probe_state = unsafe { probe_state.as_mut().unwrap() };
+    fn spawn_probe(rt: &BgRuntime, probe_state: *mut ProbeState, name: String) {
+        let Some(probe_state) = (unsafe { probe_state.as_mut() }) else {
+            return;
+        };
         let spec = probe_state.spec.clone();
         let url = probe_state.url.clone();
         let history = &probe_state.history;
         let avg = &probe_state.avg;
-        probe_state.join_handle = Some(bgt.rt.spawn(async move {
+        probe_state.join_handle = Some(rt.handle.spawn(async move {
             let mut h = 0_u64;
             for i in 0..std::cmp::min(spec.initial, 64) {
                 h |= 1 << i;
@@ -577,7 +2297,7 @@ pub mod reqwest_private {
                                     avg_rate += 1.0;
                                 }
                                 time = start.elapsed().as_secs_f64();
-                                let mut avg = avg.lock().unwrap();
+                                let mut avg = avg.lock().unwrap_or_else(PoisonError::into_inner);
                                 *avg += (time - *avg) / avg_rate;
                                 true
                             }
@@ -607,7 +2327,7 @@ pub mod reqwest_private {
                         spec.threshold,
                         spec.window,
                         time,
-                        *avg.lock().unwrap(),
+                        *avg.lock().unwrap_or_else(PoisonError::into_inner),
                         msg
                     ),
                 );
@@ -672,64 +2392,383 @@ pub mod reqwest_private {
     }
 
     impl client {
-        pub fn vcl_send(bgt: &BgThread, t: &mut VclTransaction) {
-            let old_t = std::mem::replace(t, VclTransaction::Transition);
-            *t = VclTransaction::Sent(bgt.spawn_req(old_t.into_req()));
+        // `urgent` should be set if the VCL is going to wait on the response right away
+        pub fn vcl_send(
+            &self,
+            bgt: &BgThread,
+            t: &mut VclTransaction,
+            urgent: bool,
+        ) -> VclResult<()> {
+            let mut req = t.take_req()?;
+            if req.follow.is_some() || req.proxy.is_some() {
+                match self.variant(req.follow, req.proxy.as_deref()) {
+                    Ok(client) => req.client = client,
+                    Err(e) => {
+                        *t = VclTransaction::Resp(Err(ReqError::new("builder", e.to_string())));
+                        return Ok(());
+                    }
+                }
+            }
+            *t = match bgt.spawn_req(&self.be.get_inner().runtime, req, urgent) {
+                Ok(mut pending) => {
+                    pending.cancel.on_drop = self.cancel_at_task_end;
+                    VclTransaction::Sent(pending)
+                }
+                Err(e) => VclTransaction::Resp(Err(e.into())),
+            };
+            Ok(())
+        }
+
+        // the client to use instead of ours, for requests overriding `follow` or the proxy
+        fn variant(&self, follow: Option<i64>, proxy: Option<&str>) -> VclResult<Client> {
+            self.variants.get(&self.name, follow, proxy)
         }
 
-        pub fn wait_on(bgt: &BgThread, t: &mut VclTransaction) {
+        pub fn wait_on(&self, bgt: &BgThread, t: &mut VclTransaction) -> VclResult<()> {
             match t {
                 VclTransaction::Req(_) => {
-                    Self::vcl_send(bgt, t);
-                    Self::wait_on(bgt, t);
-                }
-                VclTransaction::Sent(rx) => {
-                    *t = match rx.blocking_recv().unwrap() {
-                        RespMsg::Hdrs(resp) => VclTransaction::Resp(Ok(resp)),
-                        RespMsg::Chunk(_) => unreachable!(),
-                        RespMsg::Err(e) => {
-                            VclTransaction::Resp(Err(format!("{e}: {}", e.root_cause()).into()))
-                        }
-                    };
+                    self.vcl_send(bgt, t, true)?;
+                    self.wait_on(bgt, t)
+                }
+                VclTransaction::Sent(pending) => {
+                    pending.promote();
+                    let msg = pending.rx.blocking_recv();
+                    Self::settle(t, msg);
+                    Ok(())
+                }
+                VclTransaction::Resp(_) => Ok(()),
+                VclTransaction::Transition | VclTransaction::Transferred(_) => Err(t.unexpected()),
+            }
+        }
+
+        // turn what came out of the channel into the final response, `None` meaning the task
+        // went away without answering. Does nothing if `t` isn't in flight.
+        fn settle(t: &mut VclTransaction, msg: Option<RespMsg>) {
+            let pending = match std::mem::replace(t, VclTransaction::Transition) {
+                VclTransaction::Sent(pending) => pending,
+                old => {
+                    *t = old;
+                    return;
+                }
+            };
+            let Pending { rx, mut cancel, .. } = pending;
+            // the response is here, there's nothing left to cancel
+            cancel.on_drop = false;
+            *t = VclTransaction::Resp(match msg {
+                Some(RespMsg::Hdrs(mut resp)) => {
+                    // the body follows on the same channel
+                    resp.rest = Some(rx);
+                    Ok(*resp)
+                }
+                Some(RespMsg::Chunk(_)) => Err(ReqError::new(
+                    "other",
+                    "received body data before the response headers",
+                )),
+                Some(RespMsg::Err(e)) => Err(ReqError::with_cause(&e)),
+                None => Err(ReqError::new("abandoned", "request abandoned")),
+            });
+        }
+
+        // never blocks, and doesn't send unsent requests
+        pub fn poll_transaction(t: &mut VclTransaction) -> VclResult<bool> {
+            match t {
+                VclTransaction::Req(_) => Ok(false),
+                VclTransaction::Sent(pending) => match pending.rx.try_recv() {
+                    Ok(msg) => {
+                        Self::settle(t, Some(msg));
+                        Ok(true)
+                    }
+                    Err(TryRecvError::Empty) => Ok(false),
+                    Err(TryRecvError::Disconnected) => {
+                        Self::settle(t, None);
+                        Ok(true)
+                    }
+                },
+                VclTransaction::Resp(_) => Ok(true),
+                VclTransaction::Transition | VclTransaction::Transferred(_) => Err(t.unexpected()),
+            }
+        }
+
+        // like `wait_on()`, but gives up at `deadline`, returning whether the response arrived
+        pub fn wait_until(
+            &self,
+            bgt: &BgThread,
+            t: &mut VclTransaction,
+            deadline: Option<tokio::time::Instant>,
+        ) -> VclResult<bool> {
+            if matches!(t, VclTransaction::Req(_)) {
+                self.vcl_send(bgt, t, true)?;
+            }
+            let VclTransaction::Sent(pending) = t else {
+                return Self::poll_transaction(t);
+            };
+            pending.promote();
+            let handle = &self.be.get_inner().runtime.handle;
+            match handle.block_on(until(deadline, pending.rx.recv())) {
+                Some(msg) => {
+                    Self::settle(t, msg);
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        }
+
+        // returns the name of the first request to complete, if any does before `deadline`
+        pub fn wait_first<'a>(
+            &self,
+            bgt: &BgThread,
+            mut ts: Vec<(&'a str, &'a mut VclTransaction)>,
+            deadline: Option<tokio::time::Instant>,
+        ) -> VclResult<Option<&'a str>> {
+            for (_, t) in &mut ts {
+                if matches!(t, VclTransaction::Req(_)) {
+                    self.vcl_send(bgt, t, true)?;
+                }
+            }
+            for (name, t) in &mut ts {
+                if Self::poll_transaction(t)? {
+                    return Ok(Some(*name));
+                }
+            }
+            for (_, t) in &ts {
+                if let VclTransaction::Sent(pending) = t {
+                    pending.promote();
+                }
+            }
+            let handle = &self.be.get_inner().runtime.handle;
+            let first = std::future::poll_fn(|cx| {
+                for (i, (_, t)) in ts.iter_mut().enumerate() {
+                    if let VclTransaction::Sent(pending) = t
+                        && let Poll::Ready(msg) = pending.rx.poll_recv(cx)
+                    {
+                        return Poll::Ready((i, msg));
+                    }
                 }
-                VclTransaction::Resp(_) => (),
-                VclTransaction::Transition => panic!("impossible"),
+                Poll::Pending
+            });
+            let Some((i, msg)) = handle.block_on(until(deadline, first)) else {
+                return Ok(None);
+            };
+            let (name, t) = ts.swap_remove(i);
+            Self::settle(t, msg);
+            Ok(Some(name))
+        }
+
+        pub fn cancel_transaction(t: &mut VclTransaction) {
+            match t {
+                VclTransaction::Req(_) => (),
+                VclTransaction::Sent(pending) => pending.cancel.cancel(),
+                VclTransaction::Resp(_)
+                | VclTransaction::Transition
+                | VclTransaction::Transferred(_) => return,
             }
+            *t = VclTransaction::Resp(Err(ReqError::new("cancelled", "request cancelled")));
         }
 
         pub fn get_transaction<'a>(
             &self,
+            ctx: &Ctx,
             vp_task: &'a mut Option<Box<Vec<Entry>>>,
             name: &'a str,
         ) -> VclResult<&'a mut VclTransaction> {
-            vp_task
-                .as_mut()
-                .ok_or_else(|| {
+            let ts = vp_task.get_or_insert_default();
+            let pos = if let Some(pos) = ts
+                .iter()
+                .position(|e| name == e.req_name && self.name == e.client_name)
+            {
+                pos
+            } else {
+                let t = self.adopt(ctx, name).ok_or_else(|| {
                     <String as Into<VclError>>::into(format!(
                         "reqwest.get_transaction(): unknown request ({name})"
                     ))
-                })?
+                })?;
+                ts.push(Entry {
+                    client_name: self.name.clone(),
+                    req_name: name.to_owned(),
+                    transaction: t,
+                });
+                ts.len() - 1
+            };
+            match &mut ts[pos].transaction {
+                VclTransaction::Transferred(_) => Err(format!(
+                    "reqwest.get_transaction(): request {name} was transferred to the backend task"
+                )
+                .into()),
+                t => Ok(t),
+            }
+        }
+
+        // move request `name` out of the client task, for its backend task to pick up
+        pub fn transfer_transaction(
+            &self,
+            ctx: &Ctx,
+            vp_task: &mut Option<Box<Vec<Entry>>>,
+            name: &str,
+        ) -> VclResult<()> {
+            let Some(req) = (unsafe { ctx.raw.req.as_ref() }) else {
+                return Err(
+                    "reqwest: transfer() can only be used in client-side subroutines".into(),
+                );
+            };
+            let t = self.get_transaction(ctx, vp_task, name)?;
+            let key = (
+                req.vsl[0].wid.vxid,
+                self.name.clone(),
+                Some(name.to_owned()),
+            );
+            let old_t = std::mem::replace(t, VclTransaction::Transferred(HandOff(key.clone())));
+            let replaced = HANDOFFS
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(key, old_t);
+            drop(replaced);
+            Ok(())
+        }
+
+        // set the unsent request `name` aside, for the backend fetch to use instead of bereq
+        pub fn prepare_fetch(
+            &self,
+            ctx: &Ctx,
+            vp_task: &mut Option<Box<Vec<Entry>>>,
+            name: &str,
+        ) -> VclResult<()> {
+            let vxid = unsafe {
+                match (ctx.raw.bo.as_ref(), ctx.raw.req.as_ref()) {
+                    (Some(bo), _) => bo.vsl[0].wid.vxid,
+                    (None, Some(req)) => req.vsl[0].wid.vxid,
+                    (None, None) => {
+                        return Err("reqwest: backend_for() can only be used in a VCL task".into());
+                    }
+                }
+            };
+            let t = self.get_transaction(ctx, vp_task, name)?;
+            if !matches!(t, VclTransaction::Req(_)) {
+                return Err(
+                    format!("reqwest: backend_for(): request {name} was already sent").into(),
+                );
+            }
+            let key = (vxid, self.name.clone(), None);
+            let old_t = std::mem::replace(t, VclTransaction::Transferred(HandOff(key.clone())));
+            let replaced = HANDOFFS
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(key, old_t);
+            drop(replaced);
+            Ok(())
+        }
+
+        // backend tasks can only find their client request (and what it handed off) until the
+        // request body has been sent, i.e. in `vcl_backend_fetch`
+        fn adopt(&self, ctx: &Ctx, name: &str) -> Option<VclTransaction> {
+            let bo = unsafe { ctx.raw.bo.as_ref()? };
+            let req = unsafe { bo.req.as_ref()? };
+            let key = (
+                req.vsl[0].wid.vxid,
+                self.name.clone(),
+                Some(name.to_owned()),
+            );
+            HANDOFFS
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove(&key)
+        }
+
+        // add `t` to the task, replacing any request of the same name
+        pub fn insert_transaction(
+            &self,
+            vp_task: &mut Option<Box<Vec<Entry>>>,
+            name: &str,
+            t: VclTransaction,
+        ) {
+            let ts = vp_task.get_or_insert_default();
+            match ts
                 .iter_mut()
-                .find(|e| name == e.req_name && self.name == e.client_name)
-                .map(|e| &mut e.transaction)
-                .ok_or_else(|| {
-                    <String as Into<VclError>>::into(format!(
-                        "reqwest.get_transaction(): unknown request ({name})"
-                    ))
-                })
+                .find(|e| e.req_name == name && e.client_name == self.name)
+            {
+                None => ts.push(Entry {
+                    transaction: t,
+                    req_name: name.to_owned(),
+                    client_name: self.name.clone(),
+                }),
+                Some(e) => e.transaction = t,
+            }
+        }
+
+        pub fn get_template<'a>(
+            templates: &'a HashMap<String, Template>,
+            name: &str,
+        ) -> VclResult<&'a Template> {
+            templates
+                .get(name)
+                .ok_or_else(|| format!("reqwest: unknown template ({name})").into())
+        }
+
+        // `names` is a comma-separated list, every one of them must exist
+        pub fn get_transactions<'a>(
+            &self,
+            ctx: &Ctx,
+            vp_task: &'a mut Option<Box<Vec<Entry>>>,
+            names: &str,
+        ) -> VclResult<Vec<(&'a str, &'a mut VclTransaction)>> {
+            let names: Vec<&str> = names
+                .split(',')
+                .map(str::trim)
+                .filter(|n| !n.is_empty())
+                .collect();
+            if names.is_empty() {
+                return Err("reqwest.get_transactions(): no request names".into());
+            }
+            // check (and adopt) them one by one first
+            for name in &names {
+                self.get_transaction(ctx, vp_task, name)?;
+            }
+            let ts = vp_task
+                .iter_mut()
+                .flat_map(|v| v.iter_mut())
+                .filter(|e| self.name == e.client_name && names.contains(&e.req_name.as_str()))
+                .map(
+                    |Entry {
+                         req_name,
+                         transaction,
+                         ..
+                     }| (req_name.as_str(), transaction),
+                )
+                .collect();
+            Ok(ts)
         }
 
         // we have a stacked Result here because the first one will fail at the
         // vcl level, while the core one is salvageable
         pub fn get_resp<'a>(
             &self,
+            ctx: &Ctx,
+            vp_vcl: Option<&BgThread>,
+            vp_task: &'a mut Option<Box<Vec<Entry>>>,
+            name: &'a str,
+        ) -> VclResult<Result<&'a Response, &'a ReqError>> {
+            let t = self.get_transaction(ctx, vp_task, name)?;
+            self.wait_on(bg_thread(vp_vcl)?, t)?;
+            t.unwrap_resp()
+        }
+
+        // like `get_resp()`, but also makes sure the body was fully received; failing to do so
+        // fails the whole request
+        pub fn get_full_resp<'a>(
+            &self,
+            ctx: &Ctx,
             vp_vcl: Option<&BgThread>,
             vp_task: &'a mut Option<Box<Vec<Entry>>>,
             name: &'a str,
-        ) -> VclResult<Result<&'a Response, VclError>> {
-            let t = self.get_transaction(vp_task, name)?;
-            Self::wait_on(vp_vcl.as_ref().unwrap(), t);
-            Ok(t.unwrap_resp())
+        ) -> VclResult<Result<&'a Response, &'a ReqError>> {
+            let t = self.get_transaction(ctx, vp_task, name)?;
+            self.wait_on(bg_thread(vp_vcl)?, t)?;
+            if let VclTransaction::Resp(Ok(resp)) = t
+                && let Err(e) = resp.load_body()
+            {
+                *t = VclTransaction::Resp(Err(e));
+            }
+            t.unwrap_resp()
         }
     }
 }
@@ -1,5 +1,7 @@
 #![allow(clippy::box_collection)]
 mod implementation;
+mod relay;
+mod x509;
 
 use varnish::run_vtc_tests;
 run_vtc_tests!("tests/*.vtc");
@@ -9,17 +11,21 @@ mod reqwest {
     use std::boxed::Box;
     use std::error::Error;
     use std::io::Write;
-    use std::time::Duration;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, PoisonError, RwLock};
+    use std::time::{Duration, SystemTime};
 
-    use tokio::sync::mpsc::Sender;
     // FIXME: needed for header()
     use varnish::ffi::{VCL_BACKEND, VCL_STRING};
     use varnish::vcl::{Backend, Ctx, Event, Probe, VclError};
 
     use crate::implementation::reqwest_private::{
-        BgThread, Entry, Request, RespMsg, VCLBackend, VclTransaction, build_probe_state, client,
-        process_req,
+        BgThread, BodyMode, ClientSettings, Entry, FetchTimeouts, Phase, RedirectRules, ReqError,
+        Request, RuntimeSettings, Template, VCLBackend, Variants, VclTransaction, bg_thread,
+        build_probe_state, client, deadline, get_client, get_runtime, parse_header_list,
+        set_base_url,
     };
+    use crate::x509;
 
     impl client {
         #[allow(clippy::too_many_arguments)]
@@ -68,44 +74,46 @@ mod reqwest {
             /// - otherwise, it'll just use the `.url` field as-is (but will immediately error out if `.url` starts with a `/`).
             /// - this means `client`s without`base_url` can actually probe a another server that the one used as a backend.
             probe: Option<Probe>,
+            /// if set, the connection pool (and TLS sessions) of this `client` will be shared with the `client`s of other VCLs using the same `pool_key`, as long as they were created with the same arguments. This avoids starting from cold connections after a `vcl.load`.
+            pool_key: Option<&str>,
+            /// run the requests and the probe of this `client` on a dedicated runtime named `runtime` rather than on the default one. `client`s using the same `runtime` will share it, so a unique name isolates a `client`, for example to avoid latency-sensitive requests being starved by bulk ones. Named runtimes have the default `detached_concurrency` budget of `reqwest.configure_runtime()`.
+            runtime: Option<&str>,
+            /// number of worker threads of the dedicated runtime, defaults to the number of CPUs. Can only be used with `runtime`, and all the `client`s using the same `runtime` must agree on it.
+            runtime_threads: Option<i64>,
+            /// if set, requests that were sent but whose response wasn't received yet are cancelled when the VCL task that created them ends, instead of running to completion. Note that this includes fire-and-forget requests.
+            #[default(false)]
+            cancel_at_task_end: bool,
+            /// comma-separated list of restrictions on the redirects followed (see `follow`), a redirect breaking one of them isn't followed, and the 30X response is returned as-is:
+            /// - `same_host`: only follow redirects to the host of the original request
+            /// - `no_downgrade`: don't follow redirects from HTTPS to HTTP
+            /// - `no_private`: don't follow redirects to loopback, private or link-local IP addresses. Hostnames are checked once resolved, and one resolving to such an address fails the request with a `redirect` error (see `error_kind()`) rather than returning the 30X. A hostname the `client` already has a pooled connection to isn't resolved again, and with a proxy, only the proxy's address is resolved.
+            redirect_policy: Option<&str>,
+            /// for backend fetches, how many bytes of request body (roughly, as it's sent in pieces of up to 64KB) can be read from the client before the origin takes them. Once reached, reading the client body pauses until the origin catches up, so large uploads don't get buffered in memory.
+            #[default(1048576)]
+            req_body_buffer: i64,
+            /// for backend fetches, comma-separated list of headers to remove from both `bereq` and `beresp`, on top of the hop-by-hop ones (`connection`, `keep-alive`, `proxy-connection`, `te`, `transfer-encoding`, `upgrade` and those listed in `connection`) that are always removed.
+            strip_headers: Option<&str>,
         ) -> Result<Self, VclError> {
-            // set some default
-            let mut rcb = reqwest::ClientBuilder::new()
-                .brotli(auto_brotli)
-                .deflate(auto_deflate)
-                .gzip(auto_gzip)
-                .danger_accept_invalid_certs(accept_invalid_certs)
-                .danger_accept_invalid_hostnames(accept_invalid_hostnames);
-            if let Some(t) = timeout {
-                rcb = rcb.timeout(t);
-            }
-            if let Some(t) = connect_timeout {
-                rcb = rcb.connect_timeout(t);
-            }
-            if let Some(proxy) = http_proxy {
-                rcb = rcb.proxy(reqwest::Proxy::http(proxy).map_err(|e| {
-                    VclError::new(format!(
-                        "reqwest: couldn't initialize {vcl_name}'s HTTP proxy ({e})"
-                    ))
-                })?);
-            }
-            if let Some(proxy) = https_proxy {
-                rcb = rcb.proxy(reqwest::Proxy::https(proxy).map_err(|e| {
-                    VclError::new(format!(
-                        "reqwest: couldn't initialize {vcl_name}'s HTTPS proxy ({e})"
-                    ))
-                })?);
-            }
-            if follow <= 0 {
-                rcb = rcb.redirect(reqwest::redirect::Policy::none());
-            } else {
-                rcb = rcb.redirect(reqwest::redirect::Policy::limited(
-                    usize::try_from(follow).unwrap(),
-                ));
-            }
-            let reqwest_client = rcb.build().map_err(|e| {
-                VclError::new(format!("reqwest: couldn't initialize {vcl_name} ({e})"))
-            })?;
+            let settings = ClientSettings {
+                follow,
+                timeout,
+                connect_timeout,
+                auto_gzip,
+                auto_deflate,
+                auto_brotli,
+                accept_invalid_certs,
+                accept_invalid_hostnames,
+                http_proxy: http_proxy.map(Into::into),
+                https_proxy: https_proxy.map(Into::into),
+                redirect_rules: redirect_policy
+                    .map(RedirectRules::parse)
+                    .transpose()
+                    .map_err(|e| {
+                        VclError::new(format!("reqwest: couldn't initialize {vcl_name} ({e})"))
+                    })?
+                    .unwrap_or_default(),
+            };
+            let reqwest_client = get_client(vcl_name, &settings, pool_key)?;
 
             if https.is_some() && base_url.is_some() {
                 return Err(VclError::new(format!(
@@ -121,23 +129,75 @@ mod reqwest {
             };
             let has_probe = probe_state.is_some();
 
+            let req_body_buffer = usize::try_from(req_body_buffer)
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or_else(|| {
+                    VclError::new(format!(
+                        "reqwest: couldn't initialize {vcl_name}: req_body_buffer must be strictly positive (got {req_body_buffer})"
+                    ))
+                })?;
+
+            let bgt = bg_thread(vp_vcl.as_deref())?;
+            let rt = match (runtime, runtime_threads) {
+                (None, None) => bgt.runtime(),
+                (None, Some(_)) => Err(VclError::new(
+                    "runtime_threads requires a runtime argument".to_string(),
+                )),
+                (Some(""), _) => Err(VclError::new("runtime can't be empty".to_string())),
+                (Some(name), threads) => {
+                    let worker_threads = threads
+                        .map(|n| {
+                            usize::try_from(n).ok().filter(|n| *n > 0).ok_or_else(|| {
+                                VclError::new(format!(
+                                    "runtime_threads must be strictly positive (got {n})"
+                                ))
+                            })
+                        })
+                        .transpose()?;
+                    get_runtime(
+                        name,
+                        Some(RuntimeSettings {
+                            worker_threads,
+                            thread_name: Some(format!("reqwest-{name}")),
+                            ..Default::default()
+                        }),
+                    )
+                }
+            }
+            .map_err(|e| VclError::new(format!("reqwest: couldn't initialize {vcl_name} ({e})")))?;
+
+            let variants = Arc::new(Variants::new(settings));
             let be = Backend::new(
                 ctx,
                 "reqwest",
                 vcl_name,
                 VCLBackend {
                     name: vcl_name.to_string(),
-                    bgt: &raw const **vp_vcl.as_ref().unwrap(),
+                    bgt: &raw const *bgt,
+                    runtime: rt,
                     client: reqwest_client,
                     probe_state,
                     https: https.unwrap_or(false),
                     base_url: base_url.map(Into::into),
+                    req_body_buffer,
+                    strip_headers: strip_headers
+                        .map(parse_header_list)
+                        .transpose()
+                        .map_err(|e| {
+                            VclError::new(format!("reqwest: couldn't initialize {vcl_name} ({e})"))
+                        })?
+                        .unwrap_or_default(),
+                    variants: variants.clone(),
                 },
                 has_probe,
             )?;
             let client = client {
                 name: vcl_name.to_owned(),
                 be,
+                cancel_at_task_end,
+                templates: RwLock::default(),
+                variants,
             };
             Ok(client)
         }
@@ -150,35 +210,138 @@ mod reqwest {
             name: &str,
             /// URL/path of the request
             url: &str,
-            /// HTTP method to use
-            #[default("GET")]
-            method: &str,
-        ) {
-            if vp_task.as_ref().is_none() {
-                *vp_task = Some(Box::new(Vec::new()));
-            }
+            /// HTTP method to use, defaults to the one of `template`, or to `GET`
+            method: Option<&str>,
+            /// what to do with the response body, which never delays access to the status and headers:
+            /// - `lazy`: start downloading it in the background as soon as the headers are received, but only up to about 512KB (8 chunks of up to 64KB) ahead of what was read, the rest is downloaded when `body_as_string()` is called. The connection stays busy until the body was read in full, or until the end of the VCL task
+            /// - `buffer`: download all of it in the background as soon as the headers are received, for bodies you know you'll need
+            /// - `discard`: never download it, `body_as_string()` will return an empty string
+            ///
+            /// Behavior change: `status()`, `header()` and the other response accessors only wait for the headers, so a failure while downloading the body goes unnoticed until `body_as_string()` is called: it then returns an empty string, and from then on the request counts as failed (`status()` returns 0, `error()`/`error_kind()` report the failure). Before `body_mode` existed, the whole body was downloaded first, and such a failure was visible right away.
+            #[default("lazy")]
+            body_mode: &str,
+            /// start from the method, headers, query parameters and body of this template, see `template()`
+            template: Option<&str>,
+        ) -> Result<(), VclError> {
+            let body_mode = BodyMode::parse(body_mode)?;
+            let templates = self
+                .templates
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            let tpl = template
+                .map(|tpl| Self::get_template(&templates, tpl))
+                .transpose()?;
 
-            let ts = vp_task.as_mut().unwrap();
             let t = VclTransaction::Req(Request {
-                method: method.into(),
+                method: method
+                    .or(tpl.and_then(|tpl| tpl.method.as_deref()))
+                    .unwrap_or("GET")
+                    .into(),
                 url: url.into(),
-                headers: Vec::new(),
-                body: None,
-                client: self.be.get_inner().client.clone(),
-                vcl: true,
+                headers: tpl.map(|tpl| tpl.headers.clone()).unwrap_or_default(),
+                query: tpl.map(|tpl| tpl.query.clone()).unwrap_or_default(),
+                body: tpl.and_then(|tpl| tpl.body.clone()).map(Into::into),
+                client: reqwest::Client::clone(&self.be.get_inner().client),
+                body_mode,
+                timeout: None,
+                follow: None,
+                proxy: None,
+                fetch_timeouts: FetchTimeouts::default(),
+                body_sent: None,
             });
+            self.insert_transaction(vp_task, name, t);
+            Ok(())
+        }
 
-            match ts
-                .iter_mut()
-                .find(|e| e.req_name == name && e.client_name == self.name)
-            {
-                None => ts.push(Entry {
-                    transaction: t,
-                    req_name: name.to_owned(),
-                    client_name: self.name.clone(),
-                }),
-                Some(e) => e.transaction = t,
+        /// Create request `dst` as a copy of the unsent request `src` (method, headers, query parameters, body and `body_mode`), optionally changing its URL. As with `init()`, an existing `dst` request is dropped and replaced.
+        pub fn clone_req(
+            &self,
+            ctx: &Ctx,
+            #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
+            /// handle of the request to copy
+            src: &str,
+            /// handle of the new request
+            dst: &str,
+            /// URL of the new request, defaults to the one of `src`
+            url: Option<&str>,
+        ) -> Result<(), Box<dyn Error>> {
+            let VclTransaction::Req(req) = self.get_transaction(ctx, vp_task, src)? else {
+                return Err(src.into());
+            };
+            let mut req = req.duplicate();
+            if let Some(url) = url {
+                req.url = url.into();
             }
+            self.insert_transaction(vp_task, dst, VclTransaction::Req(req));
+            Ok(())
+        }
+
+        /// Create (or replace) the template `name`, that `init()` can start from to avoid repeating the same calls for similar requests. Can only be used in `vcl_init`.
+        #[restrict(vcl_init)]
+        pub fn template(
+            &self,
+            /// template name
+            name: &str,
+            /// default HTTP method
+            method: Option<&str>,
+            /// default body
+            body: Option<&str>,
+        ) {
+            self.templates
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(
+                    name.to_owned(),
+                    Template {
+                        method: method.map(Into::into),
+                        body: body.map(Into::into),
+                        ..Template::default()
+                    },
+                );
+        }
+
+        /// Add a header to template `name`. Can only be used in `vcl_init`.
+        #[restrict(vcl_init)]
+        pub fn template_header(
+            &self,
+            /// template name
+            name: &str,
+            /// header name
+            key: &str,
+            /// header value
+            value: &str,
+        ) -> Result<(), VclError> {
+            let mut templates = self
+                .templates
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            let tpl = templates
+                .get_mut(name)
+                .ok_or_else(|| VclError::new(format!("reqwest: unknown template ({name})")))?;
+            tpl.headers.push((key.into(), value.into()));
+            Ok(())
+        }
+
+        /// Add a query parameter to template `name`, it will be appended to the URL of the requests using it. Can only be used in `vcl_init`.
+        #[restrict(vcl_init)]
+        pub fn template_query(
+            &self,
+            /// template name
+            name: &str,
+            /// parameter name
+            key: &str,
+            /// parameter value
+            value: &str,
+        ) -> Result<(), VclError> {
+            let mut templates = self
+                .templates
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            let tpl = templates
+                .get_mut(name)
+                .ok_or_else(|| VclError::new(format!("reqwest: unknown template ({name})")))?;
+            tpl.query.push((key.into(), value.into()));
+            Ok(())
         }
 
         /// Actually send request `name`. This is non-blocking, and optional if you access the response. Any call to `status()`, `header()`, `body_as_string()` or `error()` will implicitly call `send()` if necessary and wait for the response to arrive.
@@ -186,26 +349,133 @@ mod reqwest {
         /// `send()` is mainly useful in two cases:
         /// - fire-and-forget: the response won't be checked, but you need the request to be sent away
         /// - early send: you might want to send the request in `vcl_recv` but check the response in `vcl_deliver` to parallelize the VCL processing (backend fetch et al.) with the request.
+        ///
+        /// Requests started with `send()` count against the `detached_concurrency` budget of `reqwest.configure_runtime()` until something waits on them: once the budget is exhausted, they wait for a slot, while the requests the VCL is actively waiting on (and backend fetches) start right away.
         pub fn send(
             &self,
+            ctx: &Ctx,
             #[shared_per_vcl] vp_vcl: Option<&BgThread>,
             #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
             /// request handle
             name: &str,
         ) -> Result<(), Box<dyn Error>> {
-            let t = self.get_transaction(vp_task, name)?;
+            let t = self.get_transaction(ctx, vp_task, name)?;
 
             if matches!(t, VclTransaction::Req(_)) {
-                Self::vcl_send(vp_vcl.as_ref().unwrap(), t);
-                Ok(())
+                Ok(self.vcl_send(bg_thread(vp_vcl)?, t, false)?)
             } else {
                 Err(name.into())
             }
         }
 
+        /// Cancel request `name`. If it was already sent, the underlying task is aborted, and its connection closed. Does nothing if the response was already received, otherwise `status()` will return `0` and `error()` will report the cancellation.
+        pub fn cancel(
+            &self,
+            ctx: &Ctx,
+            #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
+            /// request handle
+            name: &str,
+        ) -> Result<(), Box<dyn Error>> {
+            Self::cancel_transaction(self.get_transaction(ctx, vp_task, name)?);
+            Ok(())
+        }
+
+        /// Hand request `name` over to the backend task of the current request, so that it can be used in `vcl_backend_*` subroutines rather than in the client ones, for example to start an authentication lookup in `vcl_recv` and use its result to decide cacheability in `vcl_backend_response`.
+        ///
+        /// The request (sent or not) becomes unavailable to the client task, and the backend task adopts it the first time it's referenced. Adoption is only possible in `vcl_backend_fetch`, as the backend task loses track of the client request after that, and adopting doesn't wait for the response: use `is_ready()` or `wait()` to avoid holding the fetch back. Requests that aren't adopted (e.g. on a cache hit) are dropped at the end of the client task.
+        ///
+        /// Like every request of the backend task, an adopted request is lost after a `return (retry)`, and can't be adopted again, since the client request is out of reach by then.
+        ///
+        /// Can only be called from client-side subroutines.
+        pub fn transfer(
+            &self,
+            ctx: &Ctx,
+            #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
+            /// request handle
+            name: &str,
+        ) -> Result<(), Box<dyn Error>> {
+            Ok(self.transfer_transaction(ctx, vp_task, name)?)
+        }
+
+        /// Return whether the response of request `name` has been received (or has failed), without ever blocking. Unsent requests are not sent, and aren't ready.
+        pub fn is_ready(
+            &self,
+            ctx: &Ctx,
+            #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
+            /// request handle
+            name: &str,
+        ) -> Result<bool, Box<dyn Error>> {
+            Ok(Self::poll_transaction(
+                self.get_transaction(ctx, vp_task, name)?,
+            )?)
+        }
+
+        /// Send request `name` if necessary, and wait at most `timeout` for its response, returning whether it arrived. On `false`, the request keeps running and can be waited on again.
+        pub fn wait(
+            &self,
+            ctx: &Ctx,
+            #[shared_per_vcl] vp_vcl: Option<&BgThread>,
+            #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
+            /// request handle
+            name: &str,
+            /// how long to wait
+            timeout: Duration,
+        ) -> Result<bool, Box<dyn Error>> {
+            let deadline = deadline(timeout);
+            let t = self.get_transaction(ctx, vp_task, name)?;
+            Ok(self.wait_until(bg_thread(vp_vcl)?, t, deadline)?)
+        }
+
+        /// Send the requests in `names` if necessary, and return the name of the first one to complete (successfully or not), or NULL if none did within `timeout`. If several are already complete, the first one found is returned, so a `cancel()` on the others is a good way to only act on the first useful answer.
+        pub fn wait_any(
+            &self,
+            ctx: &Ctx,
+            #[shared_per_vcl] vp_vcl: Option<&BgThread>,
+            #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
+            /// comma-separated list of request handles
+            names: &str,
+            /// how long to wait
+            timeout: Duration,
+        ) -> Result<Option<String>, Box<dyn Error>> {
+            let deadline = deadline(timeout);
+            let ts = self.get_transactions(ctx, vp_task, names)?;
+            Ok(self
+                .wait_first(bg_thread(vp_vcl)?, ts, deadline)?
+                .map(str::to_string))
+        }
+
+        /// Send the requests in `names` if necessary, and wait at most `timeout` for all of them to complete, returning whether they did.
+        pub fn wait_all(
+            &self,
+            ctx: &Ctx,
+            #[shared_per_vcl] vp_vcl: Option<&BgThread>,
+            #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
+            /// comma-separated list of request handles
+            names: &str,
+            /// how long to wait
+            timeout: Duration,
+        ) -> Result<bool, Box<dyn Error>> {
+            let deadline = deadline(timeout);
+            let bgt = bg_thread(vp_vcl)?;
+            let mut ts = self.get_transactions(ctx, vp_task, names)?;
+            // send them all first, so they progress in parallel
+            for (_, t) in &mut ts {
+                if matches!(t, VclTransaction::Req(_)) {
+                    self.vcl_send(bgt, t, true)?;
+                }
+            }
+            for (_, t) in ts {
+                if !self.wait_until(bgt, t, deadline)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+
         /// Add a new header `name: value` to the unsent request named `name`. Calling this on a non-existing, or already sent request will trigger a VCL error.
         pub fn set_header(
             &self,
+            ctx: &Ctx,
             #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
             /// request handle
             name: &str,
@@ -214,7 +484,7 @@ mod reqwest {
             /// header value
             value: &str,
         ) -> Result<(), Box<dyn Error>> {
-            if let VclTransaction::Req(req) = self.get_transaction(vp_task, name)? {
+            if let VclTransaction::Req(req) = self.get_transaction(ctx, vp_task, name)? {
                 req.headers.push((key.into(), value.into()));
                 Ok(())
             } else {
@@ -225,13 +495,14 @@ mod reqwest {
         /// Set the body of the unsent request named `name`. As for `set_header()`, the request must exist and not have been sent.
         pub fn set_body(
             &self,
+            ctx: &Ctx,
             #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
             /// request handle
             name: &str,
             /// the body to send
             body: &str,
         ) -> Result<(), Box<dyn Error>> {
-            if let VclTransaction::Req(req) = self.get_transaction(vp_task, name)? {
+            if let VclTransaction::Req(req) = self.get_transaction(ctx, vp_task, name)? {
                 req.body = Some(Vec::from(body).into());
                 Ok(())
             } else {
@@ -239,6 +510,63 @@ mod reqwest {
             }
         }
 
+        /// Override the `timeout` of the `client` for the unsent request named `name`.
+        pub fn set_timeout(
+            &self,
+            ctx: &Ctx,
+            #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
+            /// request handle
+            name: &str,
+            /// how long the request has to finish
+            timeout: Duration,
+        ) -> Result<(), Box<dyn Error>> {
+            if let VclTransaction::Req(req) = self.get_transaction(ctx, vp_task, name)? {
+                req.timeout = Some(timeout);
+                Ok(())
+            } else {
+                Err(name.into())
+            }
+        }
+
+        /// Override the `follow` value of the `client` for the unsent request named `name`.
+        ///
+        /// As redirect policies are set on connection pools, requests using different `follow`/`set_proxy()` combinations than the `client` use separate pools, created on first use. A `client` keeps the 16 most recently used ones, so a request using an evicted combination pays for a new pool. This also applies to requests handed to the backend with `backend_for()`.
+        pub fn set_follow(
+            &self,
+            ctx: &Ctx,
+            #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
+            /// request handle
+            name: &str,
+            /// how many redirects to follow, `0` or less to not follow any
+            follow: i64,
+        ) -> Result<(), Box<dyn Error>> {
+            if let VclTransaction::Req(req) = self.get_transaction(ctx, vp_task, name)? {
+                req.follow = Some(follow);
+                Ok(())
+            } else {
+                Err(name.into())
+            }
+        }
+
+        /// Send the unsent request named `name` through the proxy at `url`, whatever its scheme, instead of the ones of the `client`. The same pool caveat as `set_follow()` applies.
+        pub fn set_proxy(
+            &self,
+            ctx: &Ctx,
+            #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
+            /// request handle
+            name: &str,
+            /// proxy URL
+            url: &str,
+        ) -> Result<(), Box<dyn Error>> {
+            reqwest::Proxy::all(url).map_err(|e| format!("invalid proxy {url} ({e})"))?;
+            if let VclTransaction::Req(req) = self.get_transaction(ctx, vp_task, name)? {
+                req.proxy = Some(url.into());
+                Ok(())
+            } else {
+                Err(name.into())
+            }
+        }
+
         /// Copy the native request headers (i.e. `req` or `bereq`) into the request named `name`.
         pub fn copy_headers_to_req(
             &self,
@@ -247,12 +575,16 @@ mod reqwest {
             /// request handle
             name: &str,
         ) -> Result<(), Box<dyn Error>> {
-            let VclTransaction::Req(req) = self.get_transaction(vp_task, name)? else {
+            let VclTransaction::Req(req) = self.get_transaction(ctx, vp_task, name)? else {
                 return Err(name.into());
             };
             // XXX: we'll always have one of those, but maybe people would want
             // `req_top`, or even `bereq` while in `vcl_pipe`?
-            let vcl_req = ctx.http_req.as_ref().or(ctx.http_bereq.as_ref()).unwrap();
+            let vcl_req = ctx
+                .http_req
+                .as_ref()
+                .or(ctx.http_bereq.as_ref())
+                .ok_or("reqwest: copy_headers_to_req(): no req or bereq to copy from")?;
 
             for hdr in vcl_req {
                 req.headers.push((hdr.0.into(), hdr.1.as_ref().into()));
@@ -264,16 +596,135 @@ mod reqwest {
         /// Retrieve the response status (send and wait if necessary), returns 0 if the response failed, but will cause a VCL error if call on a non-existing request.
         pub fn status(
             &self,
+            ctx: &Ctx,
             #[shared_per_vcl] vp_vcl: Option<&BgThread>,
             #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
             /// request handle
             name: &str,
         ) -> Result<i64, Box<dyn Error>> {
             Ok(self
-                .get_resp(vp_vcl, vp_task, name)?
+                .get_resp(ctx, vp_vcl, vp_task, name)?
                 .map_or(0, |r| r.status))
         }
 
+        /// Retrieve the URL the response came from, which differs from the one passed to `init()` if redirects were followed, or NULL if the request failed.
+        pub fn final_url(
+            &self,
+            ctx: &Ctx,
+            #[shared_per_vcl] vp_vcl: Option<&BgThread>,
+            #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
+            /// request handle
+            name: &str,
+        ) -> Result<Option<String>, Box<dyn Error>> {
+            Ok(self
+                .get_resp(ctx, vp_vcl, vp_task, name)?
+                .ok()
+                .map(|r| r.url.clone()))
+        }
+
+        /// Retrieve the number of redirects followed to get the response, 0 if the request failed.
+        pub fn redirect_count(
+            &self,
+            ctx: &Ctx,
+            #[shared_per_vcl] vp_vcl: Option<&BgThread>,
+            #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
+            /// request handle
+            name: &str,
+        ) -> Result<i64, Box<dyn Error>> {
+            Ok(self
+                .get_resp(ctx, vp_vcl, vp_task, name)?
+                .map_or(0, |r| i64::try_from(r.redirects).unwrap_or(i64::MAX)))
+        }
+
+        /// Retrieve the HTTP version of the response (e.g. "HTTP/1.1" or "HTTP/2.0"), or NULL if the request failed.
+        pub fn http_version(
+            &self,
+            ctx: &Ctx,
+            #[shared_per_vcl] vp_vcl: Option<&BgThread>,
+            #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
+            /// request handle
+            name: &str,
+        ) -> Result<Option<&'static str>, Box<dyn Error>> {
+            Ok(self
+                .get_resp(ctx, vp_vcl, vp_task, name)?
+                .ok()
+                .map(|r| r.version))
+        }
+
+        /// Retrieve the address (IP and port) of the server that sent the response, or `0.0.0.0:0` if the request failed or the address isn't known.
+        pub fn remote_addr(
+            &self,
+            ctx: &Ctx,
+            #[shared_per_vcl] vp_vcl: Option<&BgThread>,
+            #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
+            /// request handle
+            name: &str,
+        ) -> Result<SocketAddr, Box<dyn Error>> {
+            Ok(self
+                .get_resp(ctx, vp_vcl, vp_task, name)?
+                .ok()
+                .and_then(|r| r.remote_addr)
+                .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0))))
+        }
+
+        /// Retrieve the subject of the server's TLS certificate (e.g. "C=US, O=Example, CN=www.example.com"), or NULL if the request failed or wasn't made over TLS.
+        ///
+        /// Only the certificate is available, the negotiated TLS version and cipher aren't exposed by the underlying library.
+        pub fn tls_subject(
+            &self,
+            ctx: &Ctx,
+            #[shared_per_vcl] vp_vcl: Option<&BgThread>,
+            #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
+            /// request handle
+            name: &str,
+        ) -> Result<Option<String>, Box<dyn Error>> {
+            Ok(self
+                .get_resp(ctx, vp_vcl, vp_task, name)?
+                .ok()
+                .and_then(|r| r.peer_cert.as_deref())
+                .and_then(x509::parse)
+                .map(|c| c.subject))
+        }
+
+        /// Retrieve the expiry date of the server's TLS certificate, or the epoch if the request failed or wasn't made over TLS.
+        pub fn tls_expiry(
+            &self,
+            ctx: &Ctx,
+            #[shared_per_vcl] vp_vcl: Option<&BgThread>,
+            #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
+            /// request handle
+            name: &str,
+        ) -> Result<SystemTime, Box<dyn Error>> {
+            Ok(self
+                .get_resp(ctx, vp_vcl, vp_task, name)?
+                .ok()
+                .and_then(|r| r.peer_cert.as_deref())
+                .and_then(x509::parse)
+                .map_or(SystemTime::UNIX_EPOCH, |c| c.not_after))
+        }
+
+        /// Retrieve how long the request spent in `phase`, or 0 if the request failed:
+        /// - `dns`: resolving the host name, 0 if a pooled connection was used
+        /// - `connect`: opening the connection, TLS handshake included as the OpenSSL backend doesn't tell when one ends and the other starts, 0 if a pooled connection was used
+        /// - `first_byte`: from sending the request to receiving the response headers, the two previous phases included
+        /// - `body`: from the response headers to the end of the body, 0 until the body was received in full (for example with `body_as_string()`), and always 0 for `body_mode = "discard"`
+        /// - `total`: `first_byte` plus `body`
+        pub fn timing(
+            &self,
+            ctx: &Ctx,
+            #[shared_per_vcl] vp_vcl: Option<&BgThread>,
+            #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
+            /// request handle
+            name: &str,
+            /// one of `dns`, `connect`, `first_byte`, `body` or `total`
+            phase: &str,
+        ) -> Result<Duration, Box<dyn Error>> {
+            let phase = Phase::parse(phase)?;
+            Ok(self
+                .get_resp(ctx, vp_vcl, vp_task, name)?
+                .map_or(Duration::ZERO, |r| r.timing.get(phase)))
+        }
+
         /// Retrieve the value of the first header named `key`, or returns NULL if it doesn't exist, or there was a transmission error.
         pub unsafe fn header(
             &self,
@@ -288,7 +739,7 @@ mod reqwest {
             sep: Option<&str>,
         ) -> Result<VCL_STRING, VclError> {
             // get the number of headers matching, and an iterator of them
-            let (n, mut all_headers) = match self.get_resp(vp_vcl, vp_task, name)? {
+            let (n, mut all_headers) = match self.get_resp(ctx, vp_vcl, vp_task, name)? {
                 Err(_) => return Ok(VCL_STRING::default()),
                 Ok(resp) => {
                     let keys = resp.headers.get_all(key);
@@ -321,7 +772,7 @@ mod reqwest {
             }
         }
 
-        /// Retrieve the response body, returns an empty string in case of error.
+        /// Retrieve the response body (waiting for all of it to arrive), returns an empty string in case of error. Failing to download the body fails the whole request, as reported by `error()`.
         pub unsafe fn body_as_string(
             &self,
             ctx: &mut Ctx,
@@ -331,7 +782,7 @@ mod reqwest {
             name: &str,
         ) -> Result<VCL_STRING, VclError> {
             let body = self
-                .get_resp(vp_vcl, vp_task, name)
+                .get_full_resp(ctx, vp_vcl, vp_task, name)
                 .map_err(|e| VclError::new(e.to_string()))?;
             match body {
                 Err(_) => Ok(VCL_STRING::default()),
@@ -348,40 +799,180 @@ mod reqwest {
         /// Returns the error string if request `name` failed.
         pub fn error(
             &self,
+            ctx: &Ctx,
             #[shared_per_vcl] vp_vcl: Option<&BgThread>,
             #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
             /// request handle
             name: &str,
         ) -> Result<Option<String>, Box<dyn Error>> {
-            match self.get_resp(vp_vcl, vp_task, name)? {
+            match self.get_resp(ctx, vp_vcl, vp_task, name)? {
                 Err(e) => Ok(Some(e.to_string())),
                 Ok(_) => Ok(None),
             }
         }
 
+        /// Returns the category of the error if request `name` failed, as a stable string VCL can compare against, rather than having to match `error()`:
+        /// - `timeout`: the `timeout` or `connect_timeout` of the `client` (or, for backend fetches, one of the `bereq` timeouts) expired
+        /// - `dns`: the host couldn't be resolved
+        /// - `tls`: the TLS handshake failed, for example because of an invalid certificate
+        /// - `connect`: any other connection failure
+        /// - `redirect`: too many redirects, see `follow`
+        /// - `body`: sending the request body, or receiving the response body failed
+        /// - `decode`: the response body couldn't be decompressed
+        /// - `builder`: the request was invalid, for example because of a bad method or URL
+        /// - `cancelled`: `cancel()` was called
+        /// - `abandoned`: the request was interrupted, for example because the VCL went cold
+        /// - `other`: anything else
+        pub fn error_kind(
+            &self,
+            ctx: &Ctx,
+            #[shared_per_vcl] vp_vcl: Option<&BgThread>,
+            #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
+            /// request handle
+            name: &str,
+        ) -> Result<Option<&'static str>, Box<dyn Error>> {
+            match self.get_resp(ctx, vp_vcl, vp_task, name)? {
+                Err(e) => Ok(Some(e.kind)),
+                Ok(_) => Ok(None),
+            }
+        }
+
+        /// Shortcut for `error_kind(name) == "timeout"`.
+        pub fn is_timeout(
+            &self,
+            ctx: &Ctx,
+            #[shared_per_vcl] vp_vcl: Option<&BgThread>,
+            #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
+            /// request handle
+            name: &str,
+        ) -> Result<bool, Box<dyn Error>> {
+            Ok(self
+                .get_resp(ctx, vp_vcl, vp_task, name)?
+                .is_err_and(ReqError::is_timeout))
+        }
+
+        /// In backend subroutines (typically `vcl_backend_error`), return why the last fetch through this `client`'s backend failed, as `KIND: MESSAGE`, or NULL if it didn't. `KIND` is the same as `backend_error_kind()`.
+        pub fn backend_error(&self, ctx: &Ctx) -> Option<String> {
+            ReqError::get(ctx, &self.name).map(|e| format!("{}: {}", e.kind, e.msg))
+        }
+
+        /// In backend subroutines, return the category of the error reported by `backend_error()`, or NULL if there was no error:
+        /// - `unhealthy`: the probe considers the backend sick
+        /// - `no_host`: no URL could be built, see the constructor
+        /// - `req_body`: the request body couldn't be read from the client
+        /// - `invalid_bereq`: `bereq.url`, `bereq.method` or `bereq.http.host` isn't valid UTF-8
+        /// - `invalid_header`: a response header couldn't be added to `beresp`, usually because of a lack of header slots or workspace
+        /// - `abandoned`: the request task went away without answering, for example because the VCL went cold
+        /// - `timeout`, `dns`, `tls`, `connect`, `redirect`, `body`, `decode`, `builder`: the request itself failed, see `error_kind()`
+        /// - `other`: anything else
+        pub fn backend_error_kind(&self, ctx: &Ctx) -> Option<&'static str> {
+            ReqError::get(ctx, &self.name).map(|e| e.kind)
+        }
+
         /// Return a VCL backend built upon the `client` specification
-        pub unsafe fn backend(&self) -> VCL_BACKEND {
-            unsafe { self.be.as_ref().vcl_ptr() }
+        ///
+        /// `base_url`, only usable in backend subroutines (typically `vcl_backend_fetch`), overrides the `base_url` of the `client` for the current backend fetch only, for example to route requests to a tenant-specific origin. The connection pool, TLS settings and probe remain those of the `client`, and as for the constructor, `base_url` must specify a scheme and a host, and can't be used if the `client` was created with `https = true`.
+        ///
+        /// As with native backends, `bereq.connect_timeout`, `bereq.first_byte_timeout` and `bereq.between_bytes_timeout` apply to the fetch when set, on top of the `client`'s own `connect_timeout` and `timeout`. Note that `first_byte_timeout` runs from the start of the request, connection included, or, when the request body is streamed from the client, from the moment it was sent in full.
+        pub unsafe fn backend(
+            &self,
+            ctx: &Ctx,
+            base_url: Option<&str>,
+        ) -> Result<VCL_BACKEND, VclError> {
+            if let Some(base_url) = base_url {
+                if self.be.get_inner().https {
+                    return Err(VclError::new(format!(
+                        "reqwest: {}.backend(): can't use a base_url with an https client",
+                        self.name
+                    )));
+                }
+                set_base_url(ctx, &self.name, base_url)
+                    .map_err(|e| VclError::new(format!("reqwest: {}.backend(): {e}", self.name)))?;
+            }
+            Ok(unsafe { self.be.as_ref().vcl_ptr() })
+        }
+
+        /// Return the `client` backend, but have it send the unsent request `name` (URL, method, headers and body) instead of building one from `bereq`, streaming the response into the cache as usual. The request is consumed by the next backend fetch of the current task, and `bereq` is ignored, save for the timeouts. A `return (retry)` sends the same request again, unless `backend_for()` is called again to prepare another one.
+        ///
+        /// Can be used in `vcl_backend_fetch`, or client-side before the fetch starts (e.g. in `vcl_recv` or `vcl_miss`).
+        pub unsafe fn backend_for(
+            &self,
+            ctx: &Ctx,
+            #[shared_per_task] vp_task: &mut Option<Box<Vec<Entry>>>,
+            /// request handle
+            name: &str,
+        ) -> Result<VCL_BACKEND, VclError> {
+            self.prepare_fetch(ctx, vp_task, name)?;
+            Ok(unsafe { self.be.as_ref().vcl_ptr() })
+        }
+    }
+
+    /// Configure the tokio runtime used by the `client`s to process their requests. It can only be called from `vcl_init`, before any `client` is created. All arguments are optional, and the runtime uses the tokio defaults if this function isn't called.
+    ///
+    /// There is only one runtime for the whole process, shared by all the VCLs, and it's started by the first VCL creating a `client`. Later VCLs calling `configure_runtime()` must use the same settings, changing them requires restarting `varnishd`.
+    #[restrict(vcl_init)]
+    pub fn configure_runtime(
+        #[shared_per_vcl] vp_vcl: Option<&BgThread>,
+        /// number of worker threads, defaults to the number of CPUs. Can't be used with `current_thread`.
+        worker_threads: Option<i64>,
+        /// prefix used to name the runtime threads, they will be suffixed with `-<n>`
+        thread_name: Option<&str>,
+        /// maximum number of threads of the blocking pool (used for DNS resolution, notably)
+        max_blocking_threads: Option<i64>,
+        /// if set, run all requests on a single background thread instead of a pool of workers
+        #[default(false)]
+        current_thread: bool,
+        /// maximum number of fire-and-forget requests (started with `send()`, and not waited on yet) processed concurrently. Requests the VCL is waiting on, and backend fetches, don't count against this budget, and don't wait for a slot. Defaults to 256. Only applies to the default runtime, the named ones of the `runtime` argument of the `client` constructor always use the default.
+        detached_concurrency: Option<i64>,
+    ) -> Result<(), VclError> {
+        let positive = |name: &str, v: Option<i64>| {
+            v.map(|n| {
+                usize::try_from(n).ok().filter(|n| *n > 0).ok_or_else(|| {
+                    VclError::new(format!(
+                        "reqwest: configure_runtime(): {name} must be strictly positive (got {n})"
+                    ))
+                })
+            })
+            .transpose()
+        };
+        if current_thread && worker_threads.is_some() {
+            return Err(VclError::new(
+                "reqwest: configure_runtime(): can't take both a worker_threads and a current_thread argument".to_string(),
+            ));
         }
+        let settings = RuntimeSettings {
+            worker_threads: positive("worker_threads", worker_threads)?,
+            thread_name: thread_name.map(Into::into),
+            max_blocking_threads: positive("max_blocking_threads", max_blocking_threads)?,
+            current_thread,
+            detached_concurrency: positive("detached_concurrency", detached_concurrency)?,
+        };
+        bg_thread(vp_vcl)?
+            .configure(settings)
+            .map_err(|e| VclError::new(format!("reqwest: configure_runtime(): {e}")))
+    }
+
+    /// Set how long a VCL going cold waits for its in-flight requests (notably the fire-and-forget ones started with `send()`) to complete. Once the VCL is cold, no new request is accepted, and the ones still running after `grace` are abandoned and logged. Defaults to 5 seconds.
+    ///
+    /// The wait happens in the background, the temperature change itself completes right away, whatever `grace` is. Warming the VCL up again before `grace` runs out spares the in-flight requests.
+    #[restrict(vcl_init)]
+    pub fn set_drain_grace(
+        #[shared_per_vcl] vp_vcl: Option<&BgThread>,
+        grace: Duration,
+    ) -> Result<(), VclError> {
+        bg_thread(vp_vcl)?.set_drain_grace(grace);
+        Ok(())
     }
 
     #[event]
     pub fn event(#[shared_per_vcl] vp_vcl: &mut Option<Box<BgThread>>, event: Event) {
-        // we only need to worry about Load, BgThread will be destroyed with the VPriv when the VCL is
-        // discarded
-        if let Event::Load = event {
-            let rt = tokio::runtime::Runtime::new().unwrap();
-            let (sender, mut receiver) =
-                tokio::sync::mpsc::unbounded_channel::<(Request, Sender<RespMsg>)>();
-            rt.spawn(async move {
-                loop {
-                    let (req, tx) = receiver.recv().await.unwrap();
-                    tokio::spawn(async move {
-                        process_req(req, tx).await;
-                    });
-                }
-            });
-            *vp_vcl = Some(Box::new(BgThread { rt, sender }));
+        // BgThread will be destroyed with the VPriv when the VCL is discarded. The (process-wide)
+        // runtime itself is started lazily, when the first client is created.
+        match (event, vp_vcl.as_ref()) {
+            (Event::Load, _) => *vp_vcl = Some(Box::default()),
+            (Event::Warm, Some(bgt)) => bgt.resume(),
+            (Event::Cold, Some(bgt)) => bgt.drain(),
+            _ => {}
         }
     }
 }
@@ -0,0 +1,244 @@
+//! Moving response bodies from the tokio task downloading them to the Varnish worker storing
+//! them, with as few wake-ups and copies as possible. Free of any Varnish or reqwest type so
+//! that `benches/relay.rs` can exercise it directly.
+
+use std::future::Future;
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
+
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::mpsc::{Receiver, Sender};
+
+// chunks already available are merged until they reach that size, bigger ones go through as-is
+pub const COALESCE_TARGET: usize = 64 * 1024;
+// messages in flight between the task and the worker
+pub const PIPELINE_DEPTH: usize = 8;
+
+pub trait ChunkSource {
+    type Error;
+
+    // `Ok(None)` marks the end of the body, the future must be cancel-safe
+    fn chunk(&mut self) -> impl Future<Output = Result<Option<Bytes>, Self::Error>>;
+}
+
+pub trait BodyMsg: From<Bytes> {
+    type Error;
+
+    fn into_bytes(self) -> Result<Bytes, Self::Error>;
+}
+
+// poll `fut` once, without waiting for it
+fn ready_now<F: Future>(fut: F) -> Option<F::Output> {
+    match pin!(fut).poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(v) => Some(v),
+        Poll::Pending => None,
+    }
+}
+
+// forward the body of `src` to `tx`, returning `Ok(true)` once it was sent in full, and `Ok(false)`
+// if the receiver went away. Errors are returned after whatever preceded them was sent.
+pub async fn pump<S: ChunkSource, M: BodyMsg>(
+    src: &mut S,
+    tx: &Sender<M>,
+) -> Result<bool, S::Error> {
+    loop {
+        let Some(first) = src.chunk().await? else {
+            return Ok(true);
+        };
+        // only the chunks that are already there get merged, so we never wait to do it
+        let mut merged: Option<BytesMut> = None;
+        let mut end = false;
+        let mut err = None;
+        while merged.as_ref().map_or(first.len(), BytesMut::len) < COALESCE_TARGET {
+            match ready_now(src.chunk()) {
+                None => break,
+                Some(Ok(Some(more))) => merged
+                    .get_or_insert_with(|| BytesMut::from(&first[..]))
+                    .extend_from_slice(&more),
+                Some(Ok(None)) => {
+                    end = true;
+                    break;
+                }
+                Some(Err(e)) => {
+                    err = Some(e);
+                    break;
+                }
+            }
+        }
+        let chunk = merged.map_or(first, BytesMut::freeze);
+        if tx.send(chunk.into()).await.is_err() {
+            return Ok(false);
+        }
+        if let Some(e) = err {
+            return Err(e);
+        }
+        if end {
+            return Ok(true);
+        }
+    }
+}
+
+// the worker's end: what's left of the last chunk received
+#[derive(Debug, Default)]
+pub struct Drain {
+    leftover: Bytes,
+    // the body was read to the end
+    pub done: bool,
+}
+
+impl Drain {
+    // fill `buf` from `rx`, only blocking if there's nothing at all to return yet, `Ok(0)` means
+    // the body is over
+    pub fn read<M: BodyMsg>(
+        &mut self,
+        rx: &mut Receiver<M>,
+        mut buf: &mut [u8],
+    ) -> Result<usize, M::Error> {
+        let mut n = 0;
+        while !buf.is_empty() {
+            if self.leftover.is_empty() {
+                let msg = if n == 0 {
+                    rx.blocking_recv()
+                } else {
+                    match rx.try_recv() {
+                        Ok(msg) => Some(msg),
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => None,
+                    }
+                };
+                let Some(msg) = msg else {
+                    self.done = true;
+                    break;
+                };
+                self.leftover = msg.into_bytes()?;
+            }
+            let len = self.leftover.len().min(buf.len());
+            buf[..len].copy_from_slice(&self.leftover[..len]);
+            self.leftover.advance(len);
+            buf = &mut buf[len..];
+            n += len;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    impl BodyMsg for Bytes {
+        type Error = Infallible;
+
+        fn into_bytes(self) -> Result<Bytes, Infallible> {
+            Ok(self)
+        }
+    }
+
+    // an origin that always has its next chunk ready
+    struct Ready(Vec<Bytes>);
+
+    impl ChunkSource for Ready {
+        type Error = Infallible;
+
+        fn chunk(&mut self) -> impl Future<Output = Result<Option<Bytes>, Infallible>> {
+            let chunk = (!self.0.is_empty()).then(|| self.0.remove(0));
+            async move { Ok(chunk) }
+        }
+    }
+
+    // an origin whose chunks arrive when the test sends them
+    impl ChunkSource for Receiver<Bytes> {
+        type Error = Infallible;
+
+        async fn chunk(&mut self) -> Result<Option<Bytes>, Infallible> {
+            Ok(self.recv().await)
+        }
+    }
+
+    fn rt() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+    }
+
+    // the sizes of the messages `pump()` sends for `chunks`
+    fn pumped(chunks: Vec<Bytes>) -> Vec<usize> {
+        let (tx, mut rx) = mpsc::channel::<Bytes>(chunks.len().max(1));
+        assert!(rt().block_on(pump(&mut Ready(chunks), &tx)).unwrap());
+        drop(tx);
+        let mut sizes = Vec::new();
+        while let Ok(msg) = rx.try_recv() {
+            sizes.push(msg.len());
+        }
+        sizes
+    }
+
+    #[test]
+    fn merges_ready_chunks() {
+        assert_eq!(
+            pumped(vec![Bytes::from_static(b"0123456789"); 1000]),
+            [10_000]
+        );
+        assert_eq!(
+            pumped(vec![Bytes::from(vec![0; 1024]); 100]),
+            [COALESCE_TARGET, 100 * 1024 - COALESCE_TARGET]
+        );
+        assert!(pumped(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn large_chunks_go_through() {
+        let large = Bytes::from(vec![0; 4 * COALESCE_TARGET]);
+        let (tx, mut rx) = mpsc::channel::<Bytes>(PIPELINE_DEPTH);
+        let mut src = Ready(vec![large.clone(), Bytes::from_static(b"tail")]);
+        assert!(rt().block_on(pump(&mut src, &tx)).unwrap());
+        // not copied
+        assert_eq!(rx.try_recv().unwrap().as_ptr(), large.as_ptr());
+        assert_eq!(rx.try_recv().unwrap(), "tail");
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn never_waits_to_merge() {
+        let rt = rt();
+        let (origin_tx, mut origin) = mpsc::channel::<Bytes>(PIPELINE_DEPTH);
+        let (tx, mut rx) = mpsc::channel::<Bytes>(PIPELINE_DEPTH);
+        for _ in 0..3 {
+            origin_tx
+                .try_send(Bytes::from_static(b"0123456789"))
+                .unwrap();
+        }
+        let task = rt.spawn(async move { pump(&mut origin, &tx).await });
+        // what was there is sent at once, even if the origin isn't done
+        assert_eq!(rt.block_on(rx.recv()).unwrap().len(), 30);
+        origin_tx.try_send(Bytes::from_static(b"01234")).unwrap();
+        origin_tx.try_send(Bytes::from_static(b"56789")).unwrap();
+        drop(origin_tx);
+        assert_eq!(rt.block_on(rx.recv()).unwrap(), "0123456789");
+        assert!(rt.block_on(rx.recv()).is_none());
+        assert!(rt.block_on(task).unwrap().unwrap());
+    }
+
+    #[test]
+    fn drain_returns_what_is_there() {
+        let (tx, mut rx) = mpsc::channel::<Bytes>(PIPELINE_DEPTH);
+        tx.try_send(Bytes::from_static(b"0123456789")).unwrap();
+        tx.try_send(Bytes::from_static(b"abc")).unwrap();
+        let mut drain = Drain::default();
+        let mut buf = [0; 8];
+        assert_eq!(drain.read(&mut rx, &mut buf).unwrap(), 8);
+        assert_eq!(&buf, b"01234567");
+        // the leftover and the next message, without waiting for a third one
+        assert_eq!(drain.read(&mut rx, &mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"89abc");
+        assert!(!drain.done);
+        drop(tx);
+        assert_eq!(drain.read(&mut rx, &mut buf).unwrap(), 0);
+        assert!(drain.done);
+    }
+}
@@ -0,0 +1,208 @@
+//! Just enough DER to pull the subject and expiry out of a peer certificate, reqwest only hands
+//! us its raw bytes.
+
+use std::time::{Duration, SystemTime};
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const OID: u8 = 0x06;
+const EXPLICIT_VERSION: u8 = 0xa0;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+const BMP_STRING: u8 = 0x1e;
+
+#[derive(Debug, Clone)]
+pub struct PeerCert {
+    // e.g. "C=US, O=Example, CN=www.example.com"
+    pub subject: String,
+    pub not_after: SystemTime,
+}
+
+struct Der<'a>(&'a [u8]);
+
+impl<'a> Der<'a> {
+    // read the next TLV, returning its tag and content
+    fn next(&mut self) -> Option<(u8, &'a [u8])> {
+        let (&tag, rest) = self.0.split_first()?;
+        let (&first, mut rest) = rest.split_first()?;
+        let len = if first < 0x80 {
+            usize::from(first)
+        } else {
+            let n = usize::from(first & 0x7f);
+            if n == 0 || n > 4 || rest.len() < n {
+                return None;
+            }
+            let (len, tail) = rest.split_at(n);
+            rest = tail;
+            len.iter().fold(0, |acc, b| acc << 8 | usize::from(*b))
+        };
+        if rest.len() < len {
+            return None;
+        }
+        let (content, rest) = rest.split_at(len);
+        self.0 = rest;
+        Some((tag, content))
+    }
+
+    fn expect(&mut self, tag: u8) -> Option<&'a [u8]> {
+        let (t, content) = self.next()?;
+        (t == tag).then_some(content)
+    }
+}
+
+pub fn parse(der: &[u8]) -> Option<PeerCert> {
+    let cert = Der(der).expect(SEQUENCE)?;
+    let mut tbs = Der(Der(cert).expect(SEQUENCE)?);
+    // the version is optional, the serial number isn't
+    let (tag, _) = tbs.next()?;
+    if tag == EXPLICIT_VERSION {
+        tbs.next()?;
+    }
+    tbs.expect(SEQUENCE)?; // signature algorithm
+    tbs.expect(SEQUENCE)?; // issuer
+    let mut validity = Der(tbs.expect(SEQUENCE)?);
+    validity.next()?; // notBefore
+    let (tag, not_after) = validity.next()?;
+    Some(PeerCert {
+        not_after: parse_time(tag, not_after)?,
+        subject: parse_name(tbs.expect(SEQUENCE)?)?,
+    })
+}
+
+// a Name is a SEQUENCE of SETs of (OID, value) pairs, only keep the attributes we know about
+fn parse_name(name: &[u8]) -> Option<String> {
+    let mut parts = Vec::new();
+    let mut rdns = Der(name);
+    while !rdns.0.is_empty() {
+        let mut attrs = Der(rdns.expect(SET)?);
+        while !attrs.0.is_empty() {
+            let mut attr = Der(attrs.expect(SEQUENCE)?);
+            let label = match attr.expect(OID)? {
+                [0x55, 0x04, 0x03] => "CN",
+                [0x55, 0x04, 0x06] => "C",
+                [0x55, 0x04, 0x07] => "L",
+                [0x55, 0x04, 0x08] => "ST",
+                [0x55, 0x04, 0x0a] => "O",
+                [0x55, 0x04, 0x0b] => "OU",
+                _ => continue,
+            };
+            let (tag, value) = attr.next()?;
+            let value = if tag == BMP_STRING {
+                let units: Vec<u16> = value
+                    .chunks_exact(2)
+                    .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                    .collect();
+                String::from_utf16_lossy(&units)
+            } else {
+                String::from_utf8_lossy(value).into_owned()
+            };
+            parts.push(format!("{label}={value}"));
+        }
+    }
+    Some(parts.join(", "))
+}
+
+// YYMMDDHHMMSSZ or YYYYMMDDHHMMSSZ
+fn parse_time(tag: u8, time: &[u8]) -> Option<SystemTime> {
+    let s = std::str::from_utf8(time).ok()?.strip_suffix('Z')?;
+    let (year, rest): (i64, _) = match tag {
+        UTC_TIME => {
+            let yy: i64 = s.get(..2)?.parse().ok()?;
+            (if yy >= 50 { 1900 + yy } else { 2000 + yy }, s.get(2..)?)
+        }
+        GENERALIZED_TIME => (s.get(..4)?.parse().ok()?, s.get(4..)?),
+        _ => return None,
+    };
+    let field = |i: usize| -> Option<i64> { rest.get(i..i + 2)?.parse().ok() };
+    let days = days_from_civil(year, field(0)?, field(2)?);
+    let secs = days * 86_400 + field(4)? * 3_600 + field(6)? * 60 + field(8)?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+// days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // self-signed P-256 certificates made with `openssl req -x509 -not_after ...`
+    const UTC_CERT: &[u8] = include_bytes!("../tests/certs/utc_time.der");
+    const GENERALIZED_CERT: &[u8] = include_bytes!("../tests/certs/generalized_time.der");
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn utc_time() {
+        let cert = parse(UTC_CERT).unwrap();
+        assert_eq!(cert.subject, "C=US, O=Example, CN=www.example.com");
+        // 2030-12-31T23:59:59Z
+        assert_eq!(cert.not_after, at(1_924_991_999));
+    }
+
+    #[test]
+    fn generalized_time() {
+        let cert = parse(GENERALIZED_CERT).unwrap();
+        assert_eq!(
+            cert.subject,
+            "C=FR, L=Paris, O=Example, OU=Ops, CN=long.example.com"
+        );
+        // 2060-02-29T12:00:00Z, past the UTCTime range and on a leap day
+        assert_eq!(cert.not_after, at(2_845_281_600));
+    }
+
+    #[test]
+    fn truncated() {
+        for len in 0..UTC_CERT.len() {
+            assert!(parse(&UTC_CERT[..len]).is_none(), "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        // not a SEQUENCE
+        let mut der = UTC_CERT.to_vec();
+        der[0] = SET;
+        assert!(parse(&der).is_none());
+
+        // a length that doesn't fit in 4 bytes
+        assert!(parse(&[SEQUENCE, 0x85, 1, 0, 0, 0, 0]).is_none());
+
+        // an indefinite length
+        assert!(parse(&[SEQUENCE, 0x80, 0, 0]).is_none());
+
+        // garbage
+        assert!(parse(b"-----BEGIN CERTIFICATE-----").is_none());
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(
+            parse_time(UTC_TIME, b"491231235959Z"),
+            Some(at(2_524_607_999))
+        );
+        assert_eq!(
+            parse_time(GENERALIZED_TIME, b"19700101000000Z"),
+            Some(at(0))
+        );
+        // before the epoch, UTCTime years from 50 on are 19xx
+        assert_eq!(parse_time(UTC_TIME, b"500101000000Z"), None);
+        assert_eq!(parse_time(GENERALIZED_TIME, b"19691231235959Z"), None);
+        // not UTC
+        assert_eq!(parse_time(UTC_TIME, b"301231235959+0100"), None);
+        // too short
+        assert_eq!(parse_time(UTC_TIME, b"3012312359Z"), None);
+        assert_eq!(parse_time(GENERALIZED_TIME, b"301231235959Z"), None);
+        // wrong tag
+        assert_eq!(parse_time(OID, b"301231235959Z"), None);
+    }
+}